@@ -1,6 +1,15 @@
-use ic_cdk::update;
+use ic_cdk::{query, update};
 use ic_llm::{ChatMessage, Model};
 
+use crate::{
+    entities::ConversationId,
+    service::{
+        context::IcvCtx, errors::ApiResult, pagination::Cursor, ConversationService,
+        ConversationView, ConversationWithPreview, UserService, UserView,
+    },
+    utils::{count_tokens_streaming, token_count},
+};
+
 // #[update]
 // async fn prompt(prompt_str: String) -> String {
 //     ic_llm::prompt(Model::Llama3_1_8B, prompt_str).await
@@ -10,3 +19,69 @@ use ic_llm::{ChatMessage, Model};
 // async fn chat(messages: Vec<ChatMessage>) -> String {
 //     ic_llm::chat(Model::Llama3_1_8B, messages).await
 // }
+
+/// Resolves the caller, `None` for anonymous or unregistered callers.
+#[query]
+fn whoami() -> Option<UserView> {
+    UserService::default().whoami(&IcvCtx::get())
+}
+
+/// Creates a conversation owned by the caller, anonymous and unregistered callers are rejected.
+#[update]
+fn create_conversation(name: String) -> ApiResult<ConversationView> {
+    ConversationService::default().create(&IcvCtx::get(), name)
+}
+
+/// Retrieves a page of the caller conversations, `cursor` is the one returned with the
+/// previous page.
+#[query]
+fn list_conversations(
+    cursor: Option<String>,
+    limit: u64,
+) -> ApiResult<(Option<String>, Vec<ConversationWithPreview>)> {
+    let cursor = cursor.as_deref().map(Cursor::decode).transpose()?;
+    let (next, items) = ConversationService::default().list_conversations(
+        &IcvCtx::get(),
+        cursor,
+        limit as usize,
+    )?;
+    Ok((next.map(|c| c.encode()), items))
+}
+
+/// Renders a conversation of the caller as plain text, oldest turn first.
+#[query]
+fn export_plaintext(conversation: ConversationId) -> ApiResult<String> {
+    ConversationService::default().export_plaintext(&IcvCtx::get(), conversation)
+}
+
+/// Counts the tokens of a draft, for frontends to show how much of the context budget it uses.
+#[query]
+fn count_tokens(text: String) -> usize {
+    token_count(&text).unwrap_or_else(|_| count_tokens_streaming(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::ApiError, mock_ic0};
+
+    #[test]
+    fn create_conversation_should_reject_unregistered_callers() {
+        mock_ic0::reset_caller();
+        assert!(matches!(
+            create_conversation("Job hunt".to_string()),
+            Err(ApiError::IdentityNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn count_tokens_should_match_tokenizer() {
+        assert_eq!(0, count_tokens(String::new()));
+        assert_eq!(
+            7,
+            count_tokens("This is a test      with spaces".to_string())
+        );
+        assert_eq!(9, count_tokens("🎉🎉🎉".to_string()));
+        assert_eq!(10_001, count_tokens("career ".repeat(10_000)));
+    }
+}
@@ -1,4 +1,14 @@
-use std::{cell::RefCell, cmp::Reverse, str::FromStr, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    ops::RangeBounds,
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+    thread::LocalKey,
+};
 
 use bitcode::{Decode, Encode};
 use candid::{CandidType, Principal};
@@ -6,7 +16,7 @@ use ic_llm::{ChatMessage, Role};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, Memory, StableBTreeMap, StableCell, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 use itertools::Itertools;
 use lazy_static::lazy_static;
@@ -14,9 +24,13 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[cfg(all(test, not(rust_analyzer)))]
-use crate::utils::mock_ic0::timestamp;
+use crate::utils::mock_ic0::{log, timestamp};
 #[cfg(any(not(test), rust_analyzer))]
-use crate::utils::timestamp;
+use crate::utils::{log, timestamp};
+use crate::{
+    settings,
+    utils::{redact_resume, terms, BlocklistFilter, ContentFilter},
+};
 
 /// Represents a timestamp in the system.
 pub type Timestamp = u64;
@@ -43,11 +57,41 @@ pub struct Message {
     pub content: String,
     pub timestamp: Timestamp,
     pub role: Roles,
+    pub reply_to: Option<MessageId>,
+}
+
+/// Layout of [`Message`] before `reply_to` existed, kept to decode old records.
+#[derive(Encode, Decode)]
+struct MessageV0 {
+    id: MessageId,
+    conversation: u64,
+    content: String,
+    timestamp: Timestamp,
+    role: Roles,
+}
+
+impl From<MessageV0> for Message {
+    fn from(value: MessageV0) -> Self {
+        Self {
+            id: value.id,
+            conversation: value.conversation,
+            content: value.content,
+            timestamp: value.timestamp,
+            role: value.role,
+            reply_to: None,
+        }
+    }
 }
 
 /// Represents a unique identifier for a conversation.
 pub type ConversationId = u64;
 
+/// Id of an entity which has not been stored yet, serial ids start at 1.
+pub const NEW_ENTITY_ID: u64 = 0;
+
+/// Name of the conversation, owned by no user, holding the messages whose conversation is gone.
+pub const QUARANTINE_CONVERSATION_NAME: &str = "Quarantined messages";
+
 /// Struct representing a conversation between users.
 #[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, PartialEq, Eq, Debug)]
 pub struct Conversation {
@@ -55,6 +99,425 @@ pub struct Conversation {
     pub user: u64,
     pub updated_at: Timestamp,
     pub name: String,
+    pub created_at: Timestamp,
+    /// Archived conversations are kept but no longer active.
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    /// Starred conversations are the favorites of their user, unlike pinned ones they are not
+    /// capped nor shown apart in the main list.
+    #[serde(default)]
+    pub starred: bool,
+    /// Trashed conversations keep their deletion time until they are restored or purged.
+    #[serde(default)]
+    pub deleted_at: Option<Timestamp>,
+}
+
+/// Layout of [`Conversation`] before `starred` existed, kept to decode old records.
+#[derive(Encode, Decode)]
+struct ConversationV3 {
+    id: ConversationId,
+    user: u64,
+    updated_at: Timestamp,
+    name: String,
+    created_at: Timestamp,
+    archived: bool,
+    pinned: bool,
+    deleted_at: Option<Timestamp>,
+}
+
+impl From<ConversationV3> for Conversation {
+    fn from(value: ConversationV3) -> Self {
+        Self {
+            id: value.id,
+            user: value.user,
+            updated_at: value.updated_at,
+            name: value.name,
+            created_at: value.created_at,
+            archived: value.archived,
+            pinned: value.pinned,
+            starred: false,
+            deleted_at: value.deleted_at,
+        }
+    }
+}
+
+/// Layout of [`Conversation`] before `deleted_at` existed, kept to decode old records.
+#[derive(Encode, Decode)]
+struct ConversationV2 {
+    id: ConversationId,
+    user: u64,
+    updated_at: Timestamp,
+    name: String,
+    created_at: Timestamp,
+    archived: bool,
+    pinned: bool,
+}
+
+impl From<ConversationV2> for Conversation {
+    fn from(value: ConversationV2) -> Self {
+        Self {
+            id: value.id,
+            user: value.user,
+            updated_at: value.updated_at,
+            name: value.name,
+            created_at: value.created_at,
+            archived: value.archived,
+            pinned: value.pinned,
+            starred: false,
+            deleted_at: None,
+        }
+    }
+}
+
+/// Layout of [`Conversation`] before `archived` and `pinned` existed, kept to decode old records.
+#[derive(Encode, Decode)]
+struct ConversationV1 {
+    id: ConversationId,
+    user: u64,
+    updated_at: Timestamp,
+    name: String,
+    created_at: Timestamp,
+}
+
+impl From<ConversationV1> for Conversation {
+    fn from(value: ConversationV1) -> Self {
+        Self {
+            id: value.id,
+            user: value.user,
+            updated_at: value.updated_at,
+            name: value.name,
+            created_at: value.created_at,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
+        }
+    }
+}
+
+/// Layout of [`Conversation`] before `created_at` existed, kept to decode old records.
+#[derive(Encode, Decode)]
+struct ConversationV0 {
+    id: ConversationId,
+    user: u64,
+    updated_at: Timestamp,
+    name: String,
+}
+
+impl From<ConversationV0> for Conversation {
+    /// The creation time of old records is unknown, their last update is the closest guess.
+    fn from(value: ConversationV0) -> Self {
+        Self {
+            id: value.id,
+            user: value.user,
+            updated_at: value.updated_at,
+            name: value.name,
+            created_at: value.updated_at,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
+        }
+    }
+}
+
+/// Partially specified [`Message`], the id and timestamp are left for the repository to set.
+#[derive(Clone, Debug)]
+pub struct MessageBuilder {
+    conversation: ConversationId,
+    content: String,
+    role: Roles,
+    reply_to: Option<MessageId>,
+}
+
+impl Message {
+    /// Starts a user message of `conversation` with an empty content.
+    pub fn builder(conversation: ConversationId) -> MessageBuilder {
+        MessageBuilder {
+            conversation,
+            content: String::new(),
+            role: Roles::User,
+            reply_to: None,
+        }
+    }
+}
+
+impl MessageBuilder {
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.content = content.into();
+        self
+    }
+
+    pub fn role(mut self, role: Roles) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn reply_to(mut self, message: MessageId) -> Self {
+        self.reply_to = Some(message);
+        self
+    }
+
+    pub fn build(self) -> Message {
+        Message {
+            id: NEW_ENTITY_ID,
+            conversation: self.conversation,
+            content: self.content,
+            timestamp: 0,
+            role: self.role,
+            reply_to: self.reply_to,
+        }
+    }
+}
+
+/// Partially specified [`Conversation`], the id and timestamps are left for the repository
+/// to set.
+#[derive(Clone, Debug)]
+pub struct ConversationBuilder {
+    user: UserId,
+    name: String,
+    archived: bool,
+    pinned: bool,
+    starred: bool,
+}
+
+impl Conversation {
+    /// Starts an active conversation of `user` with an empty name.
+    pub fn builder(user: UserId) -> ConversationBuilder {
+        ConversationBuilder {
+            user,
+            name: String::new(),
+            archived: false,
+            pinned: false,
+            starred: false,
+        }
+    }
+}
+
+impl ConversationBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn archived(mut self, archived: bool) -> Self {
+        self.archived = archived;
+        self
+    }
+
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    pub fn starred(mut self, starred: bool) -> Self {
+        self.starred = starred;
+        self
+    }
+
+    pub fn build(self) -> Conversation {
+        Conversation {
+            id: NEW_ENTITY_ID,
+            user: self.user,
+            updated_at: 0,
+            name: self.name,
+            created_at: 0,
+            archived: self.archived,
+            pinned: self.pinned,
+            starred: self.starred,
+            deleted_at: None,
+        }
+    }
+}
+
+/// Direction in which a list is paged.
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SortDir {
+    /// Oldest first.
+    Asc,
+    /// Newest first.
+    #[default]
+    Desc,
+}
+
+/// Order of the messages matching a search.
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SearchOrder {
+    /// Newest first.
+    #[default]
+    Newest,
+    /// Highest [`relevance`] first, newest first among equal scores.
+    Relevance,
+}
+
+/// Advancement of a reindex driven over several calls.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ReindexProgress {
+    /// Values processed by the call.
+    pub processed: u64,
+    /// Id the next batch starts from.
+    pub next: u64,
+    pub done: bool,
+}
+
+/// Density of the ids of a primary map. Serial ids are never reused, so deletes leave gaps.
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct GapReport {
+    /// Lowest and highest stored ids, both zero when nothing is stored.
+    pub min: u64,
+    pub max: u64,
+    /// Ids stored between `min` and `max`.
+    pub present: u64,
+    /// Inclusive ranges of the missing ids between `min` and `max`, in ascending order.
+    pub gaps: Vec<(u64, u64)>,
+}
+
+/// Conversation stored by an upsert, telling whether it was created or updated.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct UpsertOutcome {
+    pub conversation: Conversation,
+    pub created: bool,
+}
+
+/// Represents a unique identifier for a knowledge base entry.
+pub type QaId = u64;
+
+/// Example question and answer seeded into the knowledge base.
+#[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, PartialEq, Eq, Debug)]
+pub struct QaEntry {
+    pub id: QaId,
+    pub question: String,
+    pub answer: String,
+    pub tags: Vec<String>,
+}
+
+/// Assistant persona a conversation talks to, each with its own system prompt.
+#[derive(
+    CandidType, Serialize, Deserialize, Encode, Decode, Clone, Copy, Default, PartialEq, Eq, Debug,
+)]
+pub enum Persona {
+    /// General career guidance, the default prompt.
+    #[default]
+    CareerCoach,
+    ResumeReviewer,
+    InterviewCoach,
+}
+
+/// Per conversation overrides of the deployment settings, unset fields fall back to them.
+/// An explicit `system_prompt` takes precedence over the prompt of the `persona`.
+#[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, Default, PartialEq, Debug)]
+pub struct ConversationSettings {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub system_prompt: Option<String>,
+    pub persona: Option<Persona>,
+    pub max_tokens: Option<u32>,
+}
+
+/// Legacy layout of [`ConversationSettings`], before `max_tokens`.
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+struct ConversationSettingsV1 {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub system_prompt: Option<String>,
+    pub persona: Option<Persona>,
+}
+
+impl From<ConversationSettingsV1> for ConversationSettings {
+    fn from(value: ConversationSettingsV1) -> Self {
+        Self {
+            model: value.model,
+            temperature: value.temperature,
+            system_prompt: value.system_prompt,
+            persona: value.persona,
+            max_tokens: None,
+        }
+    }
+}
+
+/// Legacy layout of [`ConversationSettings`], before personas.
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+struct ConversationSettingsV0 {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub system_prompt: Option<String>,
+}
+
+impl From<ConversationSettingsV0> for ConversationSettings {
+    fn from(value: ConversationSettingsV0) -> Self {
+        Self {
+            model: value.model,
+            temperature: value.temperature,
+            system_prompt: value.system_prompt,
+            persona: None,
+            max_tokens: None,
+        }
+    }
+}
+
+/// Tokens of the LLM turn which produced a message, zero for messages written without the LLM
+/// or before their tokens were recorded.
+#[derive(
+    CandidType, Serialize, Deserialize, Encode, Decode, Clone, Copy, Default, PartialEq, Eq, Debug,
+)]
+pub struct MessageTokens {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Message being composed on a conversation, kept until it is sent.
+#[derive(CandidType, Deserialize, Encode, Decode, Clone, PartialEq, Eq, Debug)]
+pub struct Draft {
+    pub content: String,
+    pub updated_at: Timestamp,
+}
+
+/// Summary of the oldest messages of a conversation once they outgrow the context of the
+/// model. It is kept apart from the messages and only ever sent to the LLM.
+#[derive(CandidType, Serialize, Deserialize, Encode, Decode, Clone, PartialEq, Eq, Debug)]
+pub struct Summary {
+    pub content: String,
+    /// Last message covered, the newer ones are sent as they are.
+    pub until: MessageId,
+}
+
+/// Who named a conversation last, a name given by its user is never replaced by a generated one.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TitleSource {
+    Generated,
+    Manual,
+}
+
+/// Tag applied on a conversation.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct ConversationTag {
+    pub conversation: ConversationId,
+    pub tag: String,
+}
+
+/// Entry of the tag index, a conversation of a user carrying the tag.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TaggedConversation {
+    pub user: UserId,
+    pub tag: String,
+    pub conversation: ConversationId,
+}
+
+/// Last message sent with an idempotency key on a conversation.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Debug)]
+pub struct IdempotencyRecord {
+    pub key: String,
+    pub message: MessageId,
+}
+
+/// Entry of the inverted index, a term found in a message of a conversation.
+#[derive(Encode, Decode, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct MessageTerm {
+    pub term: String,
+    pub conversation: ConversationId,
+    pub message: MessageId,
 }
 
 /// Represents a unique identifier for a user.
@@ -75,7 +538,133 @@ pub enum EntityError {
     UnknownRoles,
 }
 
+/// Stored layout starting with a schema version byte, so that records written with an older
+/// layout keep decoding once the layout changes.
+///
+/// Changing the layout means bumping [`Versioned::VERSION`], keeping the previous layout as a
+/// legacy struct and decoding it under its version in [`Versioned::decode_version`].
+trait Versioned: Sized {
+    /// Schema version of the current layout, written as the first byte of the records.
+    const VERSION: u8;
+
+    /// Decodes the payload of a record tagged with `version`, through [`decode_exact`] so that
+    /// an untagged record starting with a byte looking like a version is not mistaken for it.
+    fn decode_version(version: u8, payload: &[u8]) -> Option<Self>;
+
+    /// Decodes records written before the version byte existed.
+    fn decode_untagged(bytes: &[u8]) -> Option<Self>;
+
+    /// Encodes the value with the current layout, after its version byte.
+    fn to_versioned_bytes(&self) -> Vec<u8>
+    where
+        Self: Encode,
+    {
+        let mut bytes = vec![Self::VERSION];
+        bytes.extend(bitcode::encode(self));
+        bytes
+    }
+
+    /// Untagged records may start with a byte looking like a version, they are decoded as
+    /// untagged whenever the tagged decoding fails.
+    fn from_versioned_bytes(bytes: &[u8]) -> Self {
+        bytes
+            .split_first()
+            .and_then(|(version, payload)| Self::decode_version(*version, payload))
+            .or_else(|| Self::decode_untagged(bytes))
+            .expect("failed to decode a stored record")
+    }
+}
+
+/// Decodes a payload only when it is the exact encoding of the decoded value. Bitcode accepts
+/// payloads a legacy layout wrote by chance, re-encoding them tells the two apart.
+fn decode_exact<T>(payload: &[u8]) -> Option<T>
+where
+    T: Encode + for<'a> Decode<'a>,
+{
+    bitcode::decode::<T>(payload)
+        .ok()
+        .filter(|value| bitcode::encode(value) == payload)
+}
+
+impl Versioned for Message {
+    const VERSION: u8 = 1;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Option<Self> {
+        match version {
+            1 => decode_exact(payload),
+            _ => None,
+        }
+    }
+
+    fn decode_untagged(bytes: &[u8]) -> Option<Self> {
+        bitcode::decode(bytes)
+            .or_else(|_| bitcode::decode::<MessageV0>(bytes).map(Message::from))
+            .ok()
+    }
+}
+
+impl Versioned for Conversation {
+    const VERSION: u8 = 4;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Option<Self> {
+        match version {
+            1 => decode_exact::<ConversationV1>(payload).map(Conversation::from),
+            2 => decode_exact::<ConversationV2>(payload).map(Conversation::from),
+            3 => decode_exact::<ConversationV3>(payload).map(Conversation::from),
+            4 => decode_exact(payload),
+            _ => None,
+        }
+    }
+
+    fn decode_untagged(bytes: &[u8]) -> Option<Self> {
+        bitcode::decode::<ConversationV1>(bytes)
+            .map(Conversation::from)
+            .or_else(|_| bitcode::decode::<ConversationV0>(bytes).map(Conversation::from))
+            .ok()
+    }
+}
+
+impl Versioned for ConversationSettings {
+    const VERSION: u8 = 2;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Option<Self> {
+        match version {
+            1 => decode_exact::<ConversationSettingsV1>(payload).map(ConversationSettings::from),
+            2 => decode_exact(payload),
+            _ => None,
+        }
+    }
+
+    fn decode_untagged(bytes: &[u8]) -> Option<Self> {
+        bitcode::decode::<ConversationSettingsV0>(bytes)
+            .map(ConversationSettings::from)
+            .ok()
+    }
+}
+
 impl Storable for Message {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(self.to_versioned_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self::from_versioned_bytes(bytes.as_ref())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Conversation {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(self.to_versioned_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self::from_versioned_bytes(bytes.as_ref())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for QaEntry {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         std::borrow::Cow::Owned(bitcode::encode(self))
     }
@@ -86,7 +675,95 @@ impl Storable for Message {
     const BOUND: Bound = Bound::Unbounded;
 }
 
-impl Storable for Conversation {
+impl Storable for ConversationSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(self.to_versioned_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self::from_versioned_bytes(bytes.as_ref())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MessageTerm {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for MessageTokens {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Draft {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Summary {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TitleSource {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ConversationTag {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bitcode::encode(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bitcode::decode(bytes.as_ref()).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TaggedConversation {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         std::borrow::Cow::Owned(bitcode::encode(self))
     }
@@ -149,6 +826,10 @@ type Memo = VirtualMemory<DefaultMemoryImpl>;
 type BigSerialCell = RefCell<StableCell<u64, Memo>>;
 type BTreeMapCell<K, V> = RefCell<StableBTreeMap<K, V, Memo>>;
 type ConversationIndex = (UserId, Reverse<Timestamp>, ConversationId);
+/// Messages newest first, those sharing a timestamp are ordered by their id.
+type MessageTimestampIndex = (Reverse<Timestamp>, Reverse<MessageId>);
+/// Delete of a value owned by a user, by the time it was deleted and its id.
+type Tombstone = (UserId, Timestamp, u64);
 
 const SERIAL_CHAT_MESSAGE_MEMORY_ID: MemoryId = MemoryId::new(0);
 const SERIAL_CONVERSATION_MEMORY_ID: MemoryId = MemoryId::new(1);
@@ -159,6 +840,29 @@ const CHAT_MESSAGE_CONVERSATION_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
 const CONVERSATION_UPDATED_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
 const USER_PRINCIPAL_INDEX_MEMORY_ID: MemoryId = MemoryId::new(7);
 const SERIAL_USER_MEMORY_ID: MemoryId = MemoryId::new(8);
+const CHAT_MESSAGE_REPLY_INDEX_MEMORY_ID: MemoryId = MemoryId::new(9);
+const CONVERSATION_CREATED_INDEX_MEMORY_ID: MemoryId = MemoryId::new(10);
+const CONVERSATION_READ_MEMORY_ID: MemoryId = MemoryId::new(11);
+const SERIAL_KNOWLEDGE_MEMORY_ID: MemoryId = MemoryId::new(12);
+const KNOWLEDGE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const CONVERSATION_SUMMARY_MEMORY_ID: MemoryId = MemoryId::new(14);
+const CONVERSATION_SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const CONVERSATION_IDEMPOTENCY_MEMORY_ID: MemoryId = MemoryId::new(16);
+const CONVERSATION_STALE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(17);
+const CHAT_MESSAGE_TERM_INDEX_MEMORY_ID: MemoryId = MemoryId::new(18);
+const CHAT_MESSAGE_TIMESTAMP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(19);
+const CONVERSATION_TRASH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(20);
+const USER_TOKEN_USAGE_MEMORY_ID: MemoryId = MemoryId::new(21);
+const CONVERSATION_TAG_MEMORY_ID: MemoryId = MemoryId::new(22);
+const CONVERSATION_TAG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(23);
+const CHAT_MESSAGE_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const CONVERSATION_DRAFT_MEMORY_ID: MemoryId = MemoryId::new(25);
+const CONVERSATION_TITLE_MEMORY_ID: MemoryId = MemoryId::new(26);
+const CHAT_MESSAGE_TOMBSTONE_MEMORY_ID: MemoryId = MemoryId::new(27);
+const CONVERSATION_TOMBSTONE_MEMORY_ID: MemoryId = MemoryId::new(28);
+const USER_LAST_CREATED_MEMORY_ID: MemoryId = MemoryId::new(29);
+const USER_MESSAGE_COUNT_MEMORY_ID: MemoryId = MemoryId::new(30);
+const USER_PINNED_COUNT_MEMORY_ID: MemoryId = MemoryId::new(31);
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -218,16 +922,208 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(SERIAL_USER_MEMORY_ID)), 1
         ).expect("failed to init NEXT_USER_ID")
     );
-}
 
-#[derive(Error, Debug, Eq, PartialEq, Clone)]
-pub enum RepositoryError {
+    static CHAT_MESSAGE_REPLY_INDEX: BTreeMapCell<(MessageId, Reverse<MessageId>), ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHAT_MESSAGE_REPLY_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_CREATED_INDEX: BTreeMapCell<ConversationIndex, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_CREATED_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_READ: BTreeMapCell<(UserId, ConversationId), MessageId> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_READ_MEMORY_ID))
+        )
+    );
+
+    static NEXT_KNOWLEDGE_ID: BigSerialCell = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SERIAL_KNOWLEDGE_MEMORY_ID)), 1
+        ).expect("failed to init NEXT_KNOWLEDGE_ID")
+    );
+
+    static KNOWLEDGE: BTreeMapCell<QaId, QaEntry> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(KNOWLEDGE_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_SUMMARY: BTreeMapCell<ConversationId, Summary> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_SUMMARY_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_SETTINGS: BTreeMapCell<ConversationId, ConversationSettings> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_SETTINGS_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_IDEMPOTENCY: BTreeMapCell<ConversationId, IdempotencyRecord> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_IDEMPOTENCY_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_STALE_INDEX: BTreeMapCell<(Timestamp, ConversationId), ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_STALE_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CHAT_MESSAGE_TERM_INDEX: BTreeMapCell<MessageTerm, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHAT_MESSAGE_TERM_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CHAT_MESSAGE_TIMESTAMP_INDEX: BTreeMapCell<MessageTimestampIndex, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHAT_MESSAGE_TIMESTAMP_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_TRASH_INDEX: BTreeMapCell<ConversationIndex, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_TRASH_INDEX_MEMORY_ID))
+        )
+    );
+
+    static USER_TOKEN_USAGE: BTreeMapCell<UserId, u64> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(USER_TOKEN_USAGE_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_TAG: BTreeMapCell<ConversationTag, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_TAG_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_TAG_INDEX: BTreeMapCell<TaggedConversation, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_TAG_INDEX_MEMORY_ID))
+        )
+    );
+
+    static CHAT_MESSAGE_TOKENS: BTreeMapCell<MessageId, MessageTokens> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHAT_MESSAGE_TOKENS_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_DRAFT: BTreeMapCell<ConversationId, Draft> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_DRAFT_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_TITLE: BTreeMapCell<ConversationId, TitleSource> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_TITLE_MEMORY_ID))
+        )
+    );
+
+    static CHAT_MESSAGE_TOMBSTONE: BTreeMapCell<Tombstone, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHAT_MESSAGE_TOMBSTONE_MEMORY_ID))
+        )
+    );
+
+    static CONVERSATION_TOMBSTONE: BTreeMapCell<Tombstone, ()> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CONVERSATION_TOMBSTONE_MEMORY_ID))
+        )
+    );
+
+    static USER_LAST_CREATED: BTreeMapCell<UserId, Timestamp> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(USER_LAST_CREATED_MEMORY_ID))
+        )
+    );
+
+    static USER_MESSAGE_COUNT: BTreeMapCell<UserId, u64> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(USER_MESSAGE_COUNT_MEMORY_ID))
+        )
+    );
+
+    static USER_PINNED_COUNT: BTreeMapCell<UserId, u64> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(USER_PINNED_COUNT_MEMORY_ID))
+        )
+    );
+}
+
+thread_local! {
+    /// Conversations with a turn waiting on the LLM. Kept on the heap, a pending call does
+    /// not outlive an upgrade.
+    static CONVERSATION_IN_FLIGHT: MemoryStore<ConversationId, ()> = MemoryStore::default();
+
+    /// Recently fetched conversations, kept on the heap in front of [`CONVERSATION`].
+    static CONVERSATION_CACHE: RefCell<ConversationCache> = RefCell::new(ConversationCache::default());
+}
+
+/// Least recently used cache of conversations, the most recently used last. Every write on
+/// [`CONVERSATION`] goes through [`ConversationCache::invalidate`] so that it is never stale.
+#[derive(Default)]
+struct ConversationCache {
+    entries: VecDeque<Conversation>,
+}
+
+impl ConversationCache {
+    fn get(&mut self, id: ConversationId) -> Option<Conversation> {
+        let position = self.entries.iter().position(|c| c.id == id)?;
+        let conversation = self.entries.remove(position)?;
+        self.entries.push_back(conversation.clone());
+        Some(conversation)
+    }
+
+    fn put(&mut self, conversation: Conversation, capacity: usize) {
+        self.invalidate(conversation.id);
+        if capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(conversation);
+    }
+
+    fn invalidate(&mut self, id: ConversationId) {
+        self.entries.retain(|c| c.id != id);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Error, Debug, Eq, PartialEq, Clone)]
+pub enum RepositoryError {
     #[error(r#"The requested entity was not found in the repository."#)]
     NotFound,
     #[error(r#"Cannot write on existing entity."#)]
     Conflict,
     #[error(r#"Invalid update operation: {reason}."#)]
     IllegalUpdate { reason: String },
+    #[error(r#"Invalid reference: {reason}."#)]
+    InvalidReference { reason: String },
+    #[error(r#"Invalid data: {reason}."#)]
+    InvalidData { reason: String },
+    #[error(r#"Entity {id} belongs to another user."#)]
+    OwnershipMismatch { id: u64 },
+    #[error(r#"The quota of {limit} is exhausted."#)]
+    QuotaExceeded { limit: u64 },
+    #[error(r#"The resume takes {bytes} bytes, more than the {max} allowed."#)]
+    ResumeTooLarge { bytes: u64, max: u64 },
 }
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
@@ -241,28 +1137,30 @@ where
     fn insert(&self, value: V) -> RepositoryResult<V>;
     fn update(&self, value: V) -> RepositoryResult<V>;
     fn delete(&self, id: &K) -> RepositoryResult<K>;
+
+    /// Like [`Repository::get`], but a missing entity is a [`RepositoryError::NotFound`].
+    fn get_or_err(&self, id: &K) -> RepositoryResult<V> {
+        self.get(id).ok_or(RepositoryError::NotFound)
+    }
 }
 
-pub trait SerialIdRepository<M>
-where
-    M: Memory,
-{
+pub trait SerialIdRepository {
+    type Generator: IdGenerator;
+
     /// Wrap Serial Id data structure
-    fn with_generator<F, R>(f: F) -> R
-    where
-        F: FnOnce(&mut StableCell<u64, M>) -> R;
+    fn generator(&self) -> Self::Generator;
 
     /// Peek the next value for the serial id
     fn peek_next_id(&self) -> u64 {
-        Self::with_generator(|v| *v.get())
+        self.generator().get()
     }
 
     /// Get the next id and increment
     fn next_id(&self) -> u64 {
-        Self::with_generator(|v| {
-            let id = *v.get();
-            v.set(id + 1).unwrap()
-        })
+        let generator = self.generator();
+        let id = generator.get();
+        generator.set(id + 1);
+        id
     }
 }
 
@@ -279,6 +1177,41 @@ where
     /// Clears all the indexes
     fn clear_indexes(&self);
 
+    /// Reads `limit` values of the primary map from the id `from`, with their ids, by
+    /// ascending ids (0 reads them all), see [`slice_from`].
+    fn slice(&self, from: u64, limit: usize) -> Vec<(u64, V)>;
+
+    /// Rebuilds all the indexes from the primary map.
+    fn reindex(&self) {
+        self.clear_indexes();
+        self.slice(0, 0)
+            .iter()
+            .for_each(|(_, v)| self.add_indexes(v));
+    }
+
+    /// Adds the indexes of `limit` values of the primary map from the id `from`, so that a
+    /// large map can be reindexed over several calls, passing `next` as the following `from`.
+    /// The first call, from 0, clears the indexes so that no stale entry survives the run,
+    /// reads in between only find the values indexed so far.
+    fn reindex_batch(&self, from: u64, limit: usize) -> ReindexProgress {
+        if from == 0 {
+            self.clear_indexes();
+        }
+        let batch = self.slice(from, limit);
+        batch.iter().for_each(|(_, v)| self.add_indexes(v));
+        self.batch_progress(from, &batch)
+    }
+
+    /// Tells where a batch read by [`Self::slice`] from `from` leaves the primary map.
+    fn batch_progress(&self, from: u64, batch: &[(u64, V)]) -> ReindexProgress {
+        let next = batch.last().map_or(from, |(key, _)| key + 1);
+        ReindexProgress {
+            processed: batch.len() as u64,
+            next,
+            done: self.slice(next, 1).is_empty(),
+        }
+    }
+
     /// Saves the indexes for the current value and removes the old indexes if
     /// the value has changed.
     fn save_indexes(&self, value: &V, old_value: Option<&V>) {
@@ -289,6 +1222,62 @@ where
     }
 }
 
+/// Key of a primary map, holding the serial id of its value.
+trait SerialKey: Storable + Ord + Clone {
+    fn id(&self) -> u64;
+
+    /// Hands the entries of `map` from the id `from` to `f`, by ascending ids.
+    fn ascending_from<V, R>(
+        map: &impl Store<Self, V>,
+        from: u64,
+        f: impl FnOnce(&mut dyn Iterator<Item = (Self, V)>) -> R,
+    ) -> R;
+}
+
+impl SerialKey for u64 {
+    fn id(&self) -> u64 {
+        *self
+    }
+
+    fn ascending_from<V, R>(
+        map: &impl Store<Self, V>,
+        from: u64,
+        f: impl FnOnce(&mut dyn Iterator<Item = (Self, V)>) -> R,
+    ) -> R {
+        map.walk(from.., |mut entries| f(&mut entries))
+    }
+}
+
+impl SerialKey for Reverse<u64> {
+    fn id(&self) -> u64 {
+        self.0
+    }
+
+    fn ascending_from<V, R>(
+        map: &impl Store<Self, V>,
+        from: u64,
+        f: impl FnOnce(&mut dyn Iterator<Item = (Self, V)>) -> R,
+    ) -> R {
+        map.walk(..=Reverse(from), |entries| f(&mut entries.rev()))
+    }
+}
+
+/// Reads `limit` entries of a primary map from the id `from`, by ascending ids (0 reads them
+/// all). Seeks the id instead of skipping entries, so that a batched walk stays linear.
+fn slice_from<K, V>(map: &impl Store<K, V>, from: u64, limit: usize) -> Vec<(u64, V)>
+where
+    K: SerialKey,
+{
+    K::ascending_from(map, from, |entries| {
+        let entries = entries.map(|(key, value)| (key.id(), value));
+        if limit == usize::default() {
+            entries.collect()
+        } else {
+            entries.take(limit).collect()
+        }
+    })
+}
+
 pub trait IndexManagementRepository<I, T> {
     type Criteria;
     type Cursor;
@@ -309,636 +1298,4376 @@ pub trait IndexManagementRepository<I, T> {
     fn find(&self, criteria: Self::Criteria, cursor: Option<Self::Cursor>, limit: usize) -> Vec<T>;
 }
 
-#[derive(Default, Debug)]
-pub struct MessageConversationIndexRepository;
+/// Key-value map a repository keeps its values in, the stable memory of the canister or the
+/// heap for unit tests off the IC. Writes go through `&self`, maps sit behind a [`RefCell`].
+pub trait Store<K, V> {
+    /// Retrieves the value of a key.
+    fn get(&self, key: &K) -> Option<V>;
 
-#[derive(Default, Debug)]
-pub struct MessageRepository {
-    pub conversation_index: MessageConversationIndexRepository,
-}
+    /// Stores a value, returning the one it replaced.
+    fn insert(&self, key: K, value: V) -> Option<V>;
 
-impl IndexManagementRepository<(ConversationId, Reverse<MessageId>), MessageId>
-    for MessageConversationIndexRepository
-{
-    type Criteria = ConversationId;
-    type Cursor = MessageId;
+    /// Removes a key, returning its value.
+    fn remove(&self, key: &K) -> Option<V>;
 
-    fn exists(&self, index: &(ConversationId, Reverse<MessageId>)) -> bool {
-        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow(|m| m.get(index).is_some())
+    /// Hands the entries within `range` to `f`, in key order, `rev` walks them backwards.
+    /// Inverted bounds point at a bug in the cursor math, they are logged and hand nothing
+    /// instead of reaching the map.
+    fn walk<R>(
+        &self,
+        range: impl RangeBounds<K>,
+        f: impl FnOnce(&mut dyn DoubleEndedIterator<Item = (K, V)>) -> R,
+    ) -> R;
+
+    /// Reads the entries from `start` to `end` inclusive, in key order.
+    fn range(&self, start: K, end: K) -> Vec<(K, V)> {
+        self.walk(start..=end, |entries| entries.collect())
     }
 
-    fn insert(&self, index: (ConversationId, Reverse<MessageId>)) {
-        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.insert(index, ()));
+    /// Counts the stored entries.
+    fn len(&self) -> u64;
+
+    /// Tells whether nothing is stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    fn remove(&self, index: &(ConversationId, Reverse<MessageId>)) -> bool {
-        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.remove(index).is_some())
+    /// Tells whether a key is stored.
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
     }
 
-    fn clear(&self) {
-        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.clear_new());
+    /// Removes every entry.
+    fn clear(&self);
+}
+
+/// Tells whether the bounds of `range` are inverted, which the maps cannot walk, and logs them.
+fn inverted<K: Ord + Debug>(range: &impl RangeBounds<K>) -> bool {
+    use std::ops::Bound::{Excluded, Included};
+
+    match (range.start_bound(), range.end_bound()) {
+        (Included(start) | Excluded(start), Included(end) | Excluded(end)) if start > end => {
+            log(&format!(
+                "inverted index range from {:?} to {:?}",
+                start, end
+            ));
+            true
+        }
+        (Excluded(start), Excluded(end)) => start == end,
+        _ => false,
     }
+}
 
-    fn find(
+/// [`Store`] on one of the stable maps of the canister, the default of the repositories.
+pub type StableStore<K, V> = &'static LocalKey<BTreeMapCell<K, V>>;
+
+impl<K, V> Store<K, V> for StableStore<K, V>
+where
+    K: Storable + Ord + Clone + Debug + 'static,
+    V: Storable + 'static,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.with_borrow(|m| m.get(key))
+    }
+
+    fn insert(&self, key: K, value: V) -> Option<V> {
+        self.with_borrow_mut(|m| m.insert(key, value))
+    }
+
+    fn remove(&self, key: &K) -> Option<V> {
+        self.with_borrow_mut(|m| m.remove(key))
+    }
+
+    fn walk<R>(
         &self,
-        conversation: Self::Criteria,
-        cursor: Option<Self::Cursor>,
-        limit: usize,
-    ) -> Vec<MessageId> {
-        let last_id = cursor.map_or(MessageId::MAX, |c| c.saturating_sub(1));
-        let start = (conversation, Reverse(last_id));
-        let end = (conversation, Reverse(1));
-        if limit == usize::default() {
-            CHAT_MESSAGE_CONVERSATION_INDEX
-                .with_borrow(|m| m.range(start..=end).map(|((_, id), _)| id.0).collect_vec())
-        } else {
-            CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow(|m| {
-                m.range(start..=end)
-                    .take(limit)
-                    .map(|((_, id), _)| id.0)
-                    .collect_vec()
-            })
+        range: impl RangeBounds<K>,
+        f: impl FnOnce(&mut dyn DoubleEndedIterator<Item = (K, V)>) -> R,
+    ) -> R {
+        if inverted(&range) {
+            return f(&mut std::iter::empty::<(K, V)>());
         }
+        self.with_borrow(|m| f(&mut m.range(range)))
     }
-}
 
-impl IndexedRepository<Message> for MessageRepository {
-    fn remove_indexes(&self, value: &Message) {
-        self.conversation_index
-            .remove(&(value.conversation, Reverse(value.id)));
+    fn len(&self) -> u64 {
+        self.with_borrow(|m| m.len())
     }
 
-    fn add_indexes(&self, value: &Message) {
-        self.conversation_index
-            .insert((value.conversation, Reverse(value.id)));
+    fn clear(&self) {
+        self.with_borrow_mut(|m| m.clear_new());
     }
+}
 
-    fn clear_indexes(&self) {
-        self.conversation_index.clear();
+/// [`Store`] on the heap, for unit tests of the repositories off the IC and for the state which
+/// must not outlive an upgrade. Clones share the same map.
+#[derive(Debug)]
+pub struct MemoryStore<K, V>(Rc<RefCell<BTreeMap<K, V>>>);
+
+impl<K, V> Default for MemoryStore<K, V> {
+    fn default() -> Self {
+        Self(Rc::new(RefCell::new(BTreeMap::new())))
     }
 }
 
-impl SerialIdRepository<Memo> for MessageRepository {
-    fn with_generator<F, R>(f: F) -> R
-    where
-        F: FnOnce(&mut StableCell<u64, Memo>) -> R,
-    {
-        NEXT_CHAT_MESSAGE_ID.with_borrow_mut(|m| f(m))
+impl<K, V> Clone for MemoryStore<K, V> {
+    fn clone(&self) -> Self {
+        Self(Rc::clone(&self.0))
     }
 }
 
-impl Repository<MessageId, Message> for MessageRepository {
-    /// Retrieves a message by its ID.
-    fn get(&self, id: &MessageId) -> Option<Message> {
-        CHAT_MESSAGE.with_borrow(|m| m.get(&Reverse(*id)))
+impl<K, V> Store<K, V> for MemoryStore<K, V>
+where
+    K: Ord + Clone + Debug,
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V> {
+        self.0.borrow().get(key).cloned()
     }
 
-    /// Inserts a new message into the repository.
-    fn insert(&self, mut msg: Message) -> RepositoryResult<Message> {
-        msg.id = self.next_id();
-        msg.timestamp = timestamp();
-        let prev = CHAT_MESSAGE.with_borrow_mut(|m| m.insert(Reverse(msg.id), msg.clone()));
-        self.save_indexes(&msg, prev.as_ref());
-        Ok(msg)
+    fn insert(&self, key: K, value: V) -> Option<V> {
+        self.0.borrow_mut().insert(key, value)
     }
 
-    /// Update will always Error, because message is immutable on current design.
-    fn update(&self, value: Message) -> RepositoryResult<Message> {
-        Err(RepositoryError::IllegalUpdate {
-            reason: "Message entity cannot be updated".to_string(),
-        })
+    fn remove(&self, key: &K) -> Option<V> {
+        self.0.borrow_mut().remove(key)
     }
 
-    /// delete message by id, if such id does not exist, return NotFound error.
-    fn delete(&self, id: &MessageId) -> RepositoryResult<MessageId> {
-        let old = CHAT_MESSAGE.with_borrow_mut(|m| m.remove(&Reverse(*id)));
-        if old.is_none() {
-            Err(RepositoryError::NotFound)
-        } else {
-            self.remove_indexes(&old.unwrap());
-            Ok(*id)
+    fn walk<R>(
+        &self,
+        range: impl RangeBounds<K>,
+        f: impl FnOnce(&mut dyn DoubleEndedIterator<Item = (K, V)>) -> R,
+    ) -> R {
+        if inverted(&range) {
+            return f(&mut std::iter::empty::<(K, V)>());
         }
+        let map = self.0.borrow();
+        f(&mut map.range(range).map(|(k, v)| (k.clone(), v.clone())))
     }
-}
 
-impl MessageRepository {
-    /// Retrieves a paginated list of messages for a conversation.
-    pub fn paged_list(
-        &self,
-        conversation: ConversationId,
-        cursor: Option<MessageId>,
-        limit: usize,
-    ) -> (Option<MessageId>, Vec<Message>) {
-        let messages = self
-            .conversation_index
-            .find(conversation, cursor, limit)
-            .iter()
-            .filter_map(|id| self.get(id))
-            .collect_vec();
-        (messages.last().map(|m| m.id), messages)
+    fn len(&self) -> u64 {
+        self.0.borrow().len() as u64
     }
 
-    pub fn delete_by_conversation(
-        &self,
-        conversation: &ConversationId,
-    ) -> RepositoryResult<Vec<MessageId>> {
-        let deleted_ids = self
-            .conversation_index
-            .find(*conversation, None, 0)
-            .iter()
-            .filter_map(|id| self.delete(id).ok())
-            .collect_vec();
-        Ok(deleted_ids)
+    fn clear(&self) {
+        self.0.borrow_mut().clear();
     }
 }
 
-#[derive(Default, Debug)]
-pub struct ConversationUserIndexRepository;
+/// Counter handing out the serial ids of a primary map, see [`SerialIdRepository`].
+pub trait IdGenerator {
+    /// Reads the next id to hand out.
+    fn get(&self) -> u64;
 
-#[derive(Default, Debug)]
-pub struct ConversationRepository {
-    pub user_index: ConversationUserIndexRepository,
+    /// Moves the counter to `value`.
+    fn set(&self, value: u64);
 }
 
-impl IndexManagementRepository<ConversationIndex, ConversationId>
-    for ConversationUserIndexRepository
-{
-    type Criteria = UserId;
-    type Cursor = Timestamp;
+/// [`IdGenerator`] on one of the stable cells of the canister.
+pub type StableIdGenerator = &'static LocalKey<BigSerialCell>;
 
-    fn exists(&self, index: &ConversationIndex) -> bool {
-        CONVERSATION_USER_INDEX.with_borrow(|m| m.get(index).is_some())
+impl IdGenerator for StableIdGenerator {
+    fn get(&self) -> u64 {
+        self.with_borrow(|s| *s.get())
     }
 
-    fn insert(&self, index: ConversationIndex) {
-        CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.insert(index, ()));
+    fn set(&self, value: u64) {
+        self.with_borrow_mut(|s| s.set(value).expect("failed to set serial"));
     }
+}
 
-    fn remove(&self, index: &ConversationIndex) -> bool {
-        CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.remove(index).is_some())
+/// [`IdGenerator`] on the heap, starting at 1 like the stable ones. Clones share the counter.
+#[derive(Clone, Debug)]
+pub struct MemoryIdGenerator(Rc<Cell<u64>>);
+
+impl Default for MemoryIdGenerator {
+    fn default() -> Self {
+        Self(Rc::new(Cell::new(1)))
     }
+}
 
-    fn clear(&self) {
-        CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.clear_new());
+impl IdGenerator for MemoryIdGenerator {
+    fn get(&self) -> u64 {
+        self.0.get()
     }
 
-    fn find(
-        &self,
-        user_id: Self::Criteria,
-        cursor: Option<Timestamp>,
-        limit: usize,
-    ) -> Vec<ConversationId> {
-        let ts = cursor.map_or(Timestamp::MAX, |ts| ts - 1);
-        let start = (user_id, Reverse(ts), 0);
-        let end = (user_id, Reverse(0), ConversationId::MAX);
-
-        if limit == usize::default() {
-            CONVERSATION_USER_INDEX
-                .with_borrow(|m| m.range(start..=end).map(|((_, _, c_id), _)| c_id).collect())
-        } else {
-            CONVERSATION_USER_INDEX.with_borrow(|m| {
-                m.range(start..=end)
-                    .take(limit)
-                    .map(|((_, _, c_id), _)| c_id)
-                    .collect()
-            })
-        }
+    fn set(&self, value: u64) {
+        self.0.set(value);
     }
 }
 
-impl IndexedRepository<Conversation> for ConversationRepository {
-    fn remove_indexes(&self, conv: &Conversation) {
-        self.user_index
-            .remove(&(conv.user, Reverse(conv.updated_at), conv.id));
+/// Maps and id generators behind the repositories of the messages, conversations, users and
+/// tags, which read each other's maps: the stable memory of the canister with
+/// [`StableStorage`], or the heap with [`HeapStorage`] for unit tests off the IC.
+pub trait Storage {
+    /// Map of the storage from `K` to `V`.
+    type Map<K, V>: Store<K, V> + Clone + Debug
+    where
+        K: Storable + Ord + Clone + Debug + 'static,
+        V: Storable + Clone + Debug + 'static;
+
+    /// Map of the conversations, which may sit behind a cache.
+    type Conversations: Store<ConversationId, Conversation> + Clone + Debug;
+
+    /// Counter of the serial ids of a primary map.
+    type Generator: IdGenerator + Clone + Debug;
+
+    fn messages(&self) -> Self::Map<Reverse<MessageId>, Message>;
+    fn message_generator(&self) -> Self::Generator;
+    fn message_conversation_index(&self) -> Self::Map<(ConversationId, Reverse<MessageId>), ()>;
+    fn message_reply_index(&self) -> Self::Map<(MessageId, Reverse<MessageId>), ()>;
+    fn message_term_index(&self) -> Self::Map<MessageTerm, ()>;
+    fn message_timestamp_index(&self) -> Self::Map<MessageTimestampIndex, ()>;
+    fn message_tombstones(&self) -> Self::Map<Tombstone, ()>;
+    fn message_tokens(&self) -> Self::Map<MessageId, MessageTokens>;
+    fn conversations(&self) -> Self::Conversations;
+    /// Map of the conversations without the cache, for the reads resolving many of them at once.
+    fn conversation_map(&self) -> Self::Map<ConversationId, Conversation>;
+    fn conversation_generator(&self) -> Self::Generator;
+    fn conversation_user_index(&self) -> Self::Map<ConversationIndex, ()>;
+    fn conversation_created_index(&self) -> Self::Map<ConversationIndex, ()>;
+    fn conversation_stale_index(&self) -> Self::Map<(Timestamp, ConversationId), ()>;
+    fn conversation_trash_index(&self) -> Self::Map<ConversationIndex, ()>;
+    fn conversation_tombstones(&self) -> Self::Map<Tombstone, ()>;
+    fn conversation_tags(&self) -> Self::Map<ConversationTag, ()>;
+    fn conversation_tag_index(&self) -> Self::Map<TaggedConversation, ()>;
+    fn users(&self) -> Self::Map<UserId, User>;
+    fn user_generator(&self) -> Self::Generator;
+    fn user_identity_index(&self) -> Self::Map<(Principal, UserId), ()>;
+    fn user_message_counts(&self) -> Self::Map<UserId, u64>;
+    fn user_pinned_counts(&self) -> Self::Map<UserId, u64>;
+}
+
+/// [`Storage`] on the stable memory of the canister, the default of the repositories.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StableStorage;
+
+impl Storage for StableStorage {
+    type Map<K, V>
+        = StableStore<K, V>
+    where
+        K: Storable + Ord + Clone + Debug + 'static,
+        V: Storable + Clone + Debug + 'static;
+    type Conversations = CachedConversationStore;
+    type Generator = StableIdGenerator;
+
+    fn messages(&self) -> StableStore<Reverse<MessageId>, Message> {
+        &CHAT_MESSAGE
     }
 
-    fn add_indexes(&self, conv: &Conversation) {
-        self.user_index
-            .insert((conv.user, Reverse(conv.updated_at), conv.id));
+    fn message_generator(&self) -> StableIdGenerator {
+        &NEXT_CHAT_MESSAGE_ID
     }
 
-    fn clear_indexes(&self) {
-        self.user_index.clear();
+    fn message_conversation_index(&self) -> StableStore<(ConversationId, Reverse<MessageId>), ()> {
+        &CHAT_MESSAGE_CONVERSATION_INDEX
     }
-}
 
-impl SerialIdRepository<Memo> for ConversationRepository {
-    fn with_generator<F, R>(f: F) -> R
-    where
-        F: FnOnce(&mut StableCell<u64, Memo>) -> R,
-    {
-        NEXT_CONVERSATION_ID.with_borrow_mut(|m| f(m))
+    fn message_reply_index(&self) -> StableStore<(MessageId, Reverse<MessageId>), ()> {
+        &CHAT_MESSAGE_REPLY_INDEX
     }
-}
 
-impl Repository<ConversationId, Conversation> for ConversationRepository {
-    /// Retrieves a conversation by its ID.
-    fn get(&self, id: &ConversationId) -> Option<Conversation> {
-        CONVERSATION.with_borrow(|m| m.get(id))
+    fn message_term_index(&self) -> StableStore<MessageTerm, ()> {
+        &CHAT_MESSAGE_TERM_INDEX
     }
 
-    /// Inserts a new conversation into the repository.
-    fn insert(&self, mut conversation: Conversation) -> RepositoryResult<Conversation> {
-        conversation.id = self.next_id();
-        conversation.updated_at = timestamp();
-        let prev =
-            CONVERSATION.with_borrow_mut(|m| m.insert(conversation.id, conversation.clone()));
-        self.save_indexes(&conversation, prev.as_ref());
+    fn message_timestamp_index(&self) -> StableStore<MessageTimestampIndex, ()> {
+        &CHAT_MESSAGE_TIMESTAMP_INDEX
+    }
 
-        Ok(conversation)
+    fn message_tombstones(&self) -> StableStore<Tombstone, ()> {
+        &CHAT_MESSAGE_TOMBSTONE
     }
 
-    /// Update the conversation on the repository.
-    fn update(&self, mut conversation: Conversation) -> RepositoryResult<Conversation> {
-        if let Some(old_conv) = self.get(&conversation.id) {
-            if old_conv.user != conversation.user {
-                return Err(RepositoryError::IllegalUpdate {
-                    reason: "User is different".to_string(),
-                });
-            }
-        } else {
-            return Err(RepositoryError::NotFound);
-        }
-        conversation.updated_at = timestamp();
-        let prev =
-            CONVERSATION.with_borrow_mut(|m| m.insert(conversation.id, conversation.clone()));
-        self.save_indexes(&conversation, prev.as_ref());
+    fn message_tokens(&self) -> StableStore<MessageId, MessageTokens> {
+        &CHAT_MESSAGE_TOKENS
+    }
 
-        Ok(conversation)
+    fn conversations(&self) -> CachedConversationStore {
+        CachedConversationStore
     }
 
-    fn delete(&self, id: &ConversationId) -> RepositoryResult<ConversationId> {
-        let old = CONVERSATION.with_borrow_mut(|m| m.remove(id));
-        if old.is_none() {
-            Err(RepositoryError::NotFound)
-        } else {
-            self.remove_indexes(&old.unwrap());
-            Ok(*id)
-        }
+    fn conversation_map(&self) -> StableStore<ConversationId, Conversation> {
+        &CONVERSATION
+    }
+
+    fn conversation_generator(&self) -> StableIdGenerator {
+        &NEXT_CONVERSATION_ID
+    }
+
+    fn conversation_user_index(&self) -> StableStore<ConversationIndex, ()> {
+        &CONVERSATION_USER_INDEX
+    }
+
+    fn conversation_created_index(&self) -> StableStore<ConversationIndex, ()> {
+        &CONVERSATION_CREATED_INDEX
+    }
+
+    fn conversation_stale_index(&self) -> StableStore<(Timestamp, ConversationId), ()> {
+        &CONVERSATION_STALE_INDEX
+    }
+
+    fn conversation_trash_index(&self) -> StableStore<ConversationIndex, ()> {
+        &CONVERSATION_TRASH_INDEX
+    }
+
+    fn conversation_tombstones(&self) -> StableStore<Tombstone, ()> {
+        &CONVERSATION_TOMBSTONE
+    }
+
+    fn conversation_tags(&self) -> StableStore<ConversationTag, ()> {
+        &CONVERSATION_TAG
+    }
+
+    fn conversation_tag_index(&self) -> StableStore<TaggedConversation, ()> {
+        &CONVERSATION_TAG_INDEX
+    }
+
+    fn users(&self) -> StableStore<UserId, User> {
+        &USER
+    }
+
+    fn user_generator(&self) -> StableIdGenerator {
+        &NEXT_USER_ID
+    }
+
+    fn user_identity_index(&self) -> StableStore<(Principal, UserId), ()> {
+        &USER_PRINCIPAL_INDEX
+    }
+
+    fn user_message_counts(&self) -> StableStore<UserId, u64> {
+        &USER_MESSAGE_COUNT
+    }
+
+    fn user_pinned_counts(&self) -> StableStore<UserId, u64> {
+        &USER_PINNED_COUNT
     }
 }
 
-impl ConversationRepository {
-    /// Inserts or updates a conversation in the repository.
-    pub fn upsert(&self, conversation: Conversation) -> RepositoryResult<Conversation> {
-        match self.get(&conversation.id) {
-            Some(_) => self.update(conversation),
-            None => self.insert(conversation),
+/// [`Store`] of the conversations in stable memory, read through [`CONVERSATION_CACHE`]. Every
+/// write invalidates the cached conversation, so that the cache is never stale.
+#[derive(Clone, Copy, Debug)]
+pub struct CachedConversationStore;
+
+impl Store<ConversationId, Conversation> for CachedConversationStore {
+    fn get(&self, id: &ConversationId) -> Option<Conversation> {
+        if let Some(conversation) = CONVERSATION_CACHE.with_borrow_mut(|c| c.get(*id)) {
+            return Some(conversation);
         }
+        let conversation = CONVERSATION.with_borrow(|m| m.get(id))?;
+        let capacity = settings::get().conversation_cache_size as usize;
+        CONVERSATION_CACHE.with_borrow_mut(|c| c.put(conversation.clone(), capacity));
+        Some(conversation)
     }
 
-    /// Retrieves a paginated list of conversations for a user. Cursor is using Timestamp instead of id
-    pub fn paged_list(
+    fn insert(&self, id: ConversationId, conversation: Conversation) -> Option<Conversation> {
+        CONVERSATION_CACHE.with_borrow_mut(|c| c.invalidate(id));
+        CONVERSATION.with_borrow_mut(|m| m.insert(id, conversation))
+    }
+
+    fn remove(&self, id: &ConversationId) -> Option<Conversation> {
+        CONVERSATION_CACHE.with_borrow_mut(|c| c.invalidate(*id));
+        CONVERSATION.with_borrow_mut(|m| m.remove(id))
+    }
+
+    fn walk<R>(
         &self,
-        user_id: UserId,
-        cursor: Option<Timestamp>,
-        limit: usize,
-    ) -> (Option<Timestamp>, Vec<Conversation>) {
-        let conv = self
-            .user_index
-            .find(user_id, cursor, limit)
-            .iter()
-            .filter_map(|id| self.get(id))
-            .collect_vec();
-        (conv.last().map(|m| m.id), conv)
+        range: impl RangeBounds<ConversationId>,
+        f: impl FnOnce(&mut dyn DoubleEndedIterator<Item = (ConversationId, Conversation)>) -> R,
+    ) -> R {
+        StableStorage.conversation_map().walk(range, f)
     }
-}
 
-#[derive(Debug, Default)]
-pub struct UserIdentityIndexRepository;
+    fn len(&self) -> u64 {
+        CONVERSATION.with_borrow(|m| m.len())
+    }
 
-#[derive(Debug, Default)]
-pub struct UserRepository {
-    identity_index: UserIdentityIndexRepository,
+    fn clear(&self) {
+        CONVERSATION_CACHE.with_borrow_mut(|c| c.clear());
+        CONVERSATION.with_borrow_mut(|m| m.clear_new());
+    }
 }
 
-pub trait IdentityProvider {
-    fn get_user(&self, identity: Principal) -> Option<User>;
+/// [`Storage`] on the heap, for unit tests of the repositories off the IC. A new storage starts
+/// empty with every generator at 1, its clones share the same maps.
+#[derive(Clone, Default, Debug)]
+pub struct HeapStorage {
+    messages: MemoryStore<Reverse<MessageId>, Message>,
+    message_generator: MemoryIdGenerator,
+    message_conversation_index: MemoryStore<(ConversationId, Reverse<MessageId>), ()>,
+    message_reply_index: MemoryStore<(MessageId, Reverse<MessageId>), ()>,
+    message_term_index: MemoryStore<MessageTerm, ()>,
+    message_timestamp_index: MemoryStore<MessageTimestampIndex, ()>,
+    message_tombstones: MemoryStore<Tombstone, ()>,
+    message_tokens: MemoryStore<MessageId, MessageTokens>,
+    conversations: MemoryStore<ConversationId, Conversation>,
+    conversation_generator: MemoryIdGenerator,
+    conversation_user_index: MemoryStore<ConversationIndex, ()>,
+    conversation_created_index: MemoryStore<ConversationIndex, ()>,
+    conversation_stale_index: MemoryStore<(Timestamp, ConversationId), ()>,
+    conversation_trash_index: MemoryStore<ConversationIndex, ()>,
+    conversation_tombstones: MemoryStore<Tombstone, ()>,
+    conversation_tags: MemoryStore<ConversationTag, ()>,
+    conversation_tag_index: MemoryStore<TaggedConversation, ()>,
+    users: MemoryStore<UserId, User>,
+    user_generator: MemoryIdGenerator,
+    user_identity_index: MemoryStore<(Principal, UserId), ()>,
+    user_message_counts: MemoryStore<UserId, u64>,
+    user_pinned_counts: MemoryStore<UserId, u64>,
 }
 
-impl IndexManagementRepository<(Principal, UserId), UserId> for UserIdentityIndexRepository {
-    type Criteria = Principal;
-    type Cursor = UserId;
+impl Storage for HeapStorage {
+    type Map<K, V>
+        = MemoryStore<K, V>
+    where
+        K: Storable + Ord + Clone + Debug + 'static,
+        V: Storable + Clone + Debug + 'static;
+    type Conversations = MemoryStore<ConversationId, Conversation>;
+    type Generator = MemoryIdGenerator;
 
-    fn exists(&self, index: &(Principal, UserId)) -> bool {
-        USER_PRINCIPAL_INDEX.with_borrow(|m| m.get(index).is_some())
+    fn messages(&self) -> MemoryStore<Reverse<MessageId>, Message> {
+        self.messages.clone()
     }
 
-    fn insert(&self, index: (Principal, UserId)) {
-        USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.insert(index, ()));
+    fn message_generator(&self) -> MemoryIdGenerator {
+        self.message_generator.clone()
     }
 
-    fn remove(&self, index: &(Principal, UserId)) -> bool {
-        USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.remove(index).is_some())
+    fn message_conversation_index(&self) -> MemoryStore<(ConversationId, Reverse<MessageId>), ()> {
+        self.message_conversation_index.clone()
     }
 
-    fn clear(&self) {
-        USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.clear_new());
+    fn message_reply_index(&self) -> MemoryStore<(MessageId, Reverse<MessageId>), ()> {
+        self.message_reply_index.clone()
     }
 
-    fn find(
-        &self,
-        principal: Self::Criteria,
-        _cursor: Option<Self::Cursor>,
-        _limit: usize,
-    ) -> Vec<UserId> {
-        let start = (principal, 1);
-        let end = (principal, UserId::MAX);
-        USER_PRINCIPAL_INDEX
-            .with_borrow(|m| m.range(start..=end).map(|((_, id), _)| id).collect_vec())
+    fn message_term_index(&self) -> MemoryStore<MessageTerm, ()> {
+        self.message_term_index.clone()
     }
-}
 
-impl IndexedRepository<User> for UserRepository {
-    fn remove_indexes(&self, value: &User) {
-        self.identity_index.remove(&(value.identity, value.id));
+    fn message_timestamp_index(&self) -> MemoryStore<MessageTimestampIndex, ()> {
+        self.message_timestamp_index.clone()
     }
 
-    fn add_indexes(&self, value: &User) {
-        self.identity_index.insert((value.identity, value.id));
+    fn message_tombstones(&self) -> MemoryStore<Tombstone, ()> {
+        self.message_tombstones.clone()
     }
 
-    fn clear_indexes(&self) {
-        self.identity_index.clear();
+    fn message_tokens(&self) -> MemoryStore<MessageId, MessageTokens> {
+        self.message_tokens.clone()
     }
-}
 
-impl SerialIdRepository<Memo> for UserRepository {
-    fn with_generator<F, R>(f: F) -> R
-    where
-        F: FnOnce(&mut StableCell<u64, Memo>) -> R,
-    {
-        NEXT_USER_ID.with_borrow_mut(|m| f(m))
+    fn conversations(&self) -> MemoryStore<ConversationId, Conversation> {
+        self.conversations.clone()
     }
-}
 
-impl Repository<UserId, User> for UserRepository {
-    fn get(&self, id: &UserId) -> Option<User> {
-        USER.with_borrow(|m| m.get(id))
+    fn conversation_map(&self) -> MemoryStore<ConversationId, Conversation> {
+        self.conversations.clone()
     }
 
-    fn insert(&self, mut user: User) -> RepositoryResult<User> {
-        user.id = self.next_id();
-        let prev = USER.with_borrow_mut(|m| m.insert(user.id, user.clone()));
-        self.save_indexes(&user, prev.as_ref());
-        Ok(user)
+    fn conversation_generator(&self) -> MemoryIdGenerator {
+        self.conversation_generator.clone()
     }
 
-    fn update(&self, user: User) -> RepositoryResult<User> {
-        if self.get(&user.id).is_none() {
-            return Err(RepositoryError::NotFound);
-        }
-        let prev = USER.with_borrow_mut(|m| m.insert(user.id, user.clone()));
-        self.save_indexes(&user, prev.as_ref());
-        Ok(user)
+    fn conversation_user_index(&self) -> MemoryStore<ConversationIndex, ()> {
+        self.conversation_user_index.clone()
     }
 
-    fn delete(&self, id: &UserId) -> RepositoryResult<UserId> {
-        let old = USER.with_borrow_mut(|m| m.remove(id));
-        if old.is_none() {
-            Err(RepositoryError::NotFound)
-        } else {
-            self.remove_indexes(&old.unwrap());
-            Ok(*id)
-        }
+    fn conversation_created_index(&self) -> MemoryStore<ConversationIndex, ()> {
+        self.conversation_created_index.clone()
     }
-}
 
-impl IdentityProvider for UserRepository {
-    fn get_user(&self, identity: Principal) -> Option<User> {
-        self.identity_index
-            .find(identity, None, 0)
-            .iter()
-            .filter_map(|id| self.get(id))
-            .last()
+    fn conversation_stale_index(&self) -> MemoryStore<(Timestamp, ConversationId), ()> {
+        self.conversation_stale_index.clone()
     }
-}
 
-lazy_static! {
-    pub static ref MESSAGE_REPOSITORY: Arc<MessageRepository> =
-        Arc::new(MessageRepository::default());
-    pub static ref CONVERSATION_REPOSITORY: Arc<ConversationRepository> =
-        Arc::new(ConversationRepository::default());
-    pub static ref USER_REPOSITORY: Arc<UserRepository> = Arc::new(UserRepository::default());
-}
+    fn conversation_trash_index(&self) -> MemoryStore<ConversationIndex, ()> {
+        self.conversation_trash_index.clone()
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::utils::mock_ic0;
+    fn conversation_tombstones(&self) -> MemoryStore<Tombstone, ()> {
+        self.conversation_tombstones.clone()
+    }
 
-    use super::*;
+    fn conversation_tags(&self) -> MemoryStore<ConversationTag, ()> {
+        self.conversation_tags.clone()
+    }
 
-    fn reset_msg_data() {
-        CHAT_MESSAGE.with_borrow_mut(|m| m.clear_new());
-        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.clear_new());
-        NEXT_CHAT_MESSAGE_ID.with_borrow_mut(|v| v.set(1).unwrap());
+    fn conversation_tag_index(&self) -> MemoryStore<TaggedConversation, ()> {
+        self.conversation_tag_index.clone()
     }
 
-    fn reset_conv_data() {
-        CONVERSATION.with_borrow_mut(|m| m.clear_new());
-        CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.clear_new());
-        NEXT_CONVERSATION_ID.with_borrow_mut(|v| v.set(1).unwrap());
+    fn users(&self) -> MemoryStore<UserId, User> {
+        self.users.clone()
     }
 
-    fn reset_user_data() {
-        USER.with_borrow_mut(|m| m.clear_new());
-        USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.clear_new());
-        NEXT_USER_ID.with_borrow_mut(|m| m.set(1).unwrap());
+    fn user_generator(&self) -> MemoryIdGenerator {
+        self.user_generator.clone()
     }
 
-    #[test]
-    fn map_message_should_valid() {
-        let m = Message {
-            id: 1,
-            conversation: 1,
-            content: "hi text!".to_string(),
-            timestamp: 1,
-            role: Roles::User,
-        };
-        let mapped = m.to_ic_message();
-        assert_eq!(m.content, mapped.content);
+    fn user_identity_index(&self) -> MemoryStore<(Principal, UserId), ()> {
+        self.user_identity_index.clone()
     }
 
-    #[test]
-    fn map_role_should_valid() {
-        let r = Roles::Assistant;
-        let mapped = r.to_ic_role();
-        assert!(matches!(mapped, Role::Assistant));
-        let r = Roles::System;
-        let mapped = r.to_ic_role();
-        assert!(matches!(mapped, Role::System));
+    fn user_message_counts(&self) -> MemoryStore<UserId, u64> {
+        self.user_message_counts.clone()
     }
 
-    #[test]
-    fn parse_roles_from_str_should_works() {
-        let r = "user".parse::<Roles>().unwrap();
-        assert!(matches!(r, Roles::User));
-        let r: Roles = "assistant".parse().unwrap();
-        assert!(matches!(r, Roles::Assistant));
-        let s = String::from("system");
-        let r: Roles = s.parse().unwrap();
-        assert!(matches!(r, Roles::System));
+    fn user_pinned_counts(&self) -> MemoryStore<UserId, u64> {
+        self.user_pinned_counts.clone()
     }
+}
 
-    #[test]
-    #[should_panic]
-    fn parse_roles_from_unknown_str_should_failed() {
-        "jedi".parse::<Roles>().unwrap();
+pub trait IndexValueRepository<I, T>: IndexManagementRepository<I, T> {
+    type Value;
+
+    /// Resolves ids to values in a single pass over the primary map, missing ids are skipped.
+    fn resolve(&self, ids: Vec<T>) -> Vec<Self::Value>;
+
+    /// Finds entities based on criteria and cursor with a limit, returning values instead of ids.
+    fn find_values(
+        &self,
+        criteria: Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<Self::Value> {
+        self.resolve(self.find(criteria, cursor, limit))
     }
+}
 
-    #[test]
-    fn storable_encoding_decoding_should_valid() {
-        let message = Message {
-            id: 1,
-            conversation: 1,
-            content: "Hello, world!".to_string(),
-            timestamp: 1234567890,
-            role: Roles::User,
-        };
-        let encoded_message = message.to_bytes();
-        let decoded_message = Message::from_bytes(encoded_message);
-        assert_eq!(message, decoded_message);
+fn resolve_messages(
+    messages: &impl Store<Reverse<MessageId>, Message>,
+    ids: Vec<MessageId>,
+) -> Vec<Message> {
+    ids.into_iter()
+        .filter_map(|id| messages.get(&Reverse(id)))
+        .collect()
+}
 
-        let conversation = Conversation {
-            id: 1,
-            user: 1,
-            updated_at: 1234567890,
-            name: "Test Conversation".to_string(),
-        };
-        let encoded_conversation = conversation.to_bytes();
-        let decoded_conversation = Conversation::from_bytes(encoded_conversation);
-        assert_eq!(conversation, decoded_conversation);
+/// Reports the gaps among `ids`, expected in ascending order.
+fn gap_report(ids: impl Iterator<Item = u64>) -> GapReport {
+    let mut report = GapReport::default();
+    let mut previous = None;
+    for id in ids {
+        match previous {
+            None => report.min = id,
+            Some(previous) if id > previous + 1 => report.gaps.push((previous + 1, id - 1)),
+            Some(_) => {}
+        }
+        report.present += 1;
+        previous = Some(id);
+    }
+    report.max = previous.unwrap_or_default();
+    report
+}
+
+/// Inverted index entries of the distinct terms of a message.
+fn message_terms(message: &Message) -> impl Iterator<Item = MessageTerm> + '_ {
+    terms(&message.content).unique().map(|term| MessageTerm {
+        term,
+        conversation: message.conversation,
+        message: message.id,
+    })
+}
+
+fn resolve_conversations(
+    conversations: &impl Store<ConversationId, Conversation>,
+    ids: Vec<ConversationId>,
+) -> Vec<Conversation> {
+    ids.into_iter()
+        .filter_map(|id| conversations.get(&id))
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct MessageConversationIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<(ConversationId, Reverse<MessageId>), ()>,
+    messages: S::Map<Reverse<MessageId>, Message>,
+}
+
+#[derive(Debug)]
+pub struct MessageReplyIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<(MessageId, Reverse<MessageId>), ()>,
+    messages: S::Map<Reverse<MessageId>, Message>,
+}
+
+/// Inverted index of the terms of the messages, by conversation.
+#[derive(Debug)]
+pub struct MessageTermIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<MessageTerm, ()>,
+    messages: S::Map<Reverse<MessageId>, Message>,
+}
+
+/// Index of every message by insertion time, newest first. Messages inserted at the same
+/// time are ordered by id, so pages never skip nor repeat them.
+#[derive(Debug)]
+pub struct MessageTimestampIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<MessageTimestampIndex, ()>,
+    messages: S::Map<Reverse<MessageId>, Message>,
+}
+
+/// Deleted messages of each user, so that delta syncs can tell clients to drop them.
+#[derive(Debug)]
+pub struct MessageTombstoneRepository<S: Storage = StableStorage> {
+    tombstones: S::Map<Tombstone, ()>,
+}
+
+#[derive(Debug)]
+pub struct MessageRepository<S: Storage = StableStorage> {
+    pub conversation_index: MessageConversationIndexRepository<S>,
+    pub reply_index: MessageReplyIndexRepository<S>,
+    pub term_index: MessageTermIndexRepository<S>,
+    pub timestamp_index: MessageTimestampIndexRepository<S>,
+    pub tombstones: MessageTombstoneRepository<S>,
+    messages: S::Map<Reverse<MessageId>, Message>,
+    tokens: S::Map<MessageId, MessageTokens>,
+    conversations: S::Map<ConversationId, Conversation>,
+    /// Messages held by the conversations of each user, trashed ones included. Kept along with
+    /// the indexes, a reindex of the messages rebuilds it.
+    user_counts: S::Map<UserId, u64>,
+    generator: S::Generator,
+}
+
+impl Default for MessageRepository {
+    fn default() -> Self {
+        Self::with_storage(&StableStorage)
+    }
+}
+
+impl<S: Storage> MessageConversationIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.message_conversation_index(),
+            messages: storage.messages(),
+        }
+    }
+}
+
+impl<S: Storage> MessageReplyIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.message_reply_index(),
+            messages: storage.messages(),
+        }
+    }
+}
+
+impl<S: Storage> MessageTermIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.message_term_index(),
+            messages: storage.messages(),
+        }
+    }
+}
+
+impl<S: Storage> MessageTimestampIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.message_timestamp_index(),
+            messages: storage.messages(),
+        }
+    }
+}
+
+impl<S: Storage> MessageTombstoneRepository<S> {
+    /// Builds the repository on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            tombstones: storage.message_tombstones(),
+        }
+    }
+
+    /// Records that a message of `user` was deleted now.
+    pub fn record(&self, user: UserId, message: MessageId) {
+        record_tombstone(&self.tombstones, user, message);
+    }
+
+    /// Finds the messages of `user` deleted at `since` or later, oldest delete first.
+    pub fn since(&self, user: UserId, since: Timestamp) -> Vec<MessageId> {
+        tombstones_since(&self.tombstones, user, since)
+    }
+
+    /// Forgets the messages of every user deleted before `before`. Returns how many were pruned.
+    pub fn prune_before(&self, before: Timestamp) -> u64 {
+        prune_tombstones(&self.tombstones, before)
+    }
+}
+
+fn record_tombstone(map: &impl Store<Tombstone, ()>, user: UserId, id: u64) {
+    map.insert((user, timestamp(), id), ());
+}
+
+fn tombstones_since(map: &impl Store<Tombstone, ()>, user: UserId, since: Timestamp) -> Vec<u64> {
+    map.walk(
+        (user, since, 0)..=(user, Timestamp::MAX, u64::MAX),
+        |entries| entries.map(|((_, _, id), _)| id).collect(),
+    )
+}
+
+/// Removes the tombstones recorded before `before`. They are keyed by user first, so every
+/// tombstone is scanned.
+fn prune_tombstones(map: &impl Store<Tombstone, ()>, before: Timestamp) -> u64 {
+    let expired: Vec<Tombstone> = map.walk(.., |entries| {
+        entries
+            .map(|(key, _)| key)
+            .filter(|(_, deleted, _)| *deleted < before)
+            .collect()
+    });
+    expired.iter().for_each(|key| {
+        map.remove(key);
+    });
+    expired.len() as u64
+}
+
+impl<S: Storage> IndexManagementRepository<(ConversationId, Reverse<MessageId>), MessageId>
+    for MessageConversationIndexRepository<S>
+{
+    type Criteria = ConversationId;
+    type Cursor = MessageId;
+
+    fn exists(&self, index: &(ConversationId, Reverse<MessageId>)) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: (ConversationId, Reverse<MessageId>)) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &(ConversationId, Reverse<MessageId>)) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        conversation: Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<MessageId> {
+        let last_id = cursor.map_or(MessageId::MAX, |c| c.saturating_sub(1));
+        let start = (conversation, Reverse(last_id));
+        let end = (conversation, Reverse(0));
+        if limit == usize::default() {
+            self.index.walk(start..=end, |entries| {
+                entries.map(|((_, id), _)| id.0).collect_vec()
+            })
+        } else {
+            self.index.walk(start..=end, |entries| {
+                entries.take(limit).map(|((_, id), _)| id.0).collect_vec()
+            })
+        }
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<(MessageId, Reverse<MessageId>), MessageId>
+    for MessageReplyIndexRepository<S>
+{
+    type Criteria = MessageId;
+    type Cursor = MessageId;
+
+    fn exists(&self, index: &(MessageId, Reverse<MessageId>)) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: (MessageId, Reverse<MessageId>)) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &(MessageId, Reverse<MessageId>)) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        parent: Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<MessageId> {
+        let last_id = cursor.map_or(MessageId::MAX, |c| c.saturating_sub(1));
+        let start = (parent, Reverse(last_id));
+        let end = (parent, Reverse(1));
+        if limit == usize::default() {
+            self.index.walk(start..=end, |entries| {
+                entries.map(|((_, id), _)| id.0).collect_vec()
+            })
+        } else {
+            self.index.walk(start..=end, |entries| {
+                entries.take(limit).map(|((_, id), _)| id.0).collect_vec()
+            })
+        }
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<MessageTerm, MessageId>
+    for MessageTermIndexRepository<S>
+{
+    type Criteria = (String, ConversationId);
+    type Cursor = MessageId;
+
+    fn exists(&self, index: &MessageTerm) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: MessageTerm) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &MessageTerm) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    /// Finds the messages of a conversation holding the term, newest first.
+    fn find(
+        &self,
+        (term, conversation): Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<MessageId> {
+        let start = MessageTerm {
+            term: term.clone(),
+            conversation,
+            message: MessageId::default(),
+        };
+        let end = MessageTerm {
+            term,
+            conversation,
+            message: cursor.unwrap_or(MessageId::MAX),
+        };
+        let mut ids = self.index.walk(start..end, |entries| {
+            entries.map(|(t, _)| t.message).collect_vec()
+        });
+        ids.reverse();
+        if limit != usize::default() {
+            ids.truncate(limit);
+        }
+        ids
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<MessageTimestampIndex, (Timestamp, MessageId)>
+    for MessageTimestampIndexRepository<S>
+{
+    /// Messages inserted at or after this time are found.
+    type Criteria = Timestamp;
+    type Cursor = (Timestamp, MessageId);
+
+    fn exists(&self, index: &MessageTimestampIndex) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: MessageTimestampIndex) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &MessageTimestampIndex) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        since: Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<(Timestamp, MessageId)> {
+        let start = cursor.map_or(
+            (Reverse(Timestamp::MAX), Reverse(MessageId::MAX)),
+            |(ts, id)| (Reverse(ts), Reverse(id.saturating_sub(1))),
+        );
+        let end = (Reverse(since), Reverse(0));
+        if start > end {
+            return vec![];
+        }
+
+        if limit == usize::default() {
+            self.index.walk(start..=end, |entries| {
+                entries.map(|((ts, id), _)| (ts.0, id.0)).collect()
+            })
+        } else {
+            self.index.walk(start..=end, |entries| {
+                entries
+                    .take(limit)
+                    .map(|((ts, id), _)| (ts.0, id.0))
+                    .collect()
+            })
+        }
+    }
+}
+
+impl<S: Storage> IndexValueRepository<MessageTimestampIndex, (Timestamp, MessageId)>
+    for MessageTimestampIndexRepository<S>
+{
+    type Value = Message;
+
+    fn resolve(&self, ids: Vec<(Timestamp, MessageId)>) -> Vec<Message> {
+        resolve_messages(&self.messages, ids.into_iter().map(|(_, id)| id).collect())
+    }
+}
+
+impl<S: Storage> IndexValueRepository<MessageTerm, MessageId> for MessageTermIndexRepository<S> {
+    type Value = Message;
+
+    fn resolve(&self, ids: Vec<MessageId>) -> Vec<Message> {
+        resolve_messages(&self.messages, ids)
+    }
+}
+
+impl<S: Storage> IndexValueRepository<(ConversationId, Reverse<MessageId>), MessageId>
+    for MessageConversationIndexRepository<S>
+{
+    type Value = Message;
+
+    fn resolve(&self, ids: Vec<MessageId>) -> Vec<Message> {
+        resolve_messages(&self.messages, ids)
+    }
+}
+
+impl<S: Storage> IndexValueRepository<(MessageId, Reverse<MessageId>), MessageId>
+    for MessageReplyIndexRepository<S>
+{
+    type Value = Message;
+
+    fn resolve(&self, ids: Vec<MessageId>) -> Vec<Message> {
+        resolve_messages(&self.messages, ids)
+    }
+}
+
+impl<S: Storage> IndexedRepository<Message> for MessageRepository<S> {
+    fn remove_indexes(&self, value: &Message) {
+        self.conversation_index
+            .remove(&(value.conversation, Reverse(value.id)));
+        if let Some(parent) = value.reply_to {
+            self.reply_index.remove(&(parent, Reverse(value.id)));
+        }
+        message_terms(value).for_each(|t| {
+            self.term_index.remove(&t);
+        });
+        self.timestamp_index
+            .remove(&(Reverse(value.timestamp), Reverse(value.id)));
+        if let Some(conversation) = self.conversations.get(&value.conversation) {
+            match self.count_by_user(conversation.user) {
+                0 | 1 => self.user_counts.remove(&conversation.user),
+                count => self.user_counts.insert(conversation.user, count - 1),
+            };
+        }
+    }
+
+    fn add_indexes(&self, value: &Message) {
+        self.conversation_index
+            .insert((value.conversation, Reverse(value.id)));
+        if let Some(parent) = value.reply_to {
+            self.reply_index.insert((parent, Reverse(value.id)));
+        }
+        message_terms(value).for_each(|t| self.term_index.insert(t));
+        self.timestamp_index
+            .insert((Reverse(value.timestamp), Reverse(value.id)));
+        if let Some(conversation) = self.conversations.get(&value.conversation) {
+            let count = self.count_by_user(conversation.user);
+            self.user_counts.insert(conversation.user, count + 1);
+        }
+    }
+
+    fn clear_indexes(&self) {
+        self.conversation_index.clear();
+        self.reply_index.clear();
+        self.term_index.clear();
+        self.timestamp_index.clear();
+        self.user_counts.clear();
+    }
+
+    fn slice(&self, from: u64, limit: usize) -> Vec<(u64, Message)> {
+        slice_from(&self.messages, from, limit)
+    }
+}
+
+impl<S: Storage> SerialIdRepository for MessageRepository<S> {
+    type Generator = S::Generator;
+
+    fn generator(&self) -> S::Generator {
+        self.generator.clone()
+    }
+}
+
+impl<S: Storage> Repository<MessageId, Message> for MessageRepository<S> {
+    /// Retrieves a message by its ID.
+    fn get(&self, id: &MessageId) -> Option<Message> {
+        self.messages.get(&Reverse(*id))
+    }
+
+    /// Inserts a new message into the repository.
+    /// A `reply_to` must point to an existing message of the same conversation, and the
+    /// `max_messages` setting caps the amount of stored messages.
+    fn insert(&self, mut msg: Message) -> RepositoryResult<Message> {
+        let limit = settings::get().max_messages;
+        if limit != 0 && self.count() >= limit {
+            return Err(RepositoryError::QuotaExceeded { limit });
+        }
+        if let Some(parent) = msg.reply_to {
+            if self
+                .get(&parent)
+                .is_none_or(|p| p.conversation != msg.conversation)
+            {
+                return Err(RepositoryError::InvalidReference {
+                    reason: format!(
+                        "message {} is not part of conversation {}",
+                        parent, msg.conversation
+                    ),
+                });
+            }
+        }
+        msg.id = self.next_id();
+        msg.timestamp = timestamp();
+        let prev = self.messages.insert(Reverse(msg.id), msg.clone());
+        self.save_indexes(&msg, prev.as_ref());
+        Ok(msg)
+    }
+
+    /// Update will always Error, because message is immutable on current design.
+    fn update(&self, value: Message) -> RepositoryResult<Message> {
+        Err(RepositoryError::IllegalUpdate {
+            reason: "Message entity cannot be updated".to_string(),
+        })
+    }
+
+    /// Deletes a message along with its indexes and recorded tokens, and leaves a tombstone
+    /// for the owner of its conversation while the conversation still exists.
+    fn delete(&self, id: &MessageId) -> RepositoryResult<MessageId> {
+        let old = self.messages.remove(&Reverse(*id));
+        if old.is_none() {
+            Err(RepositoryError::NotFound)
+        } else {
+            let old = old.unwrap();
+            self.remove_indexes(&old);
+            self.tokens.remove(id);
+            if let Some(conversation) = self.conversations.get(&old.conversation) {
+                self.tombstones.record(conversation.user, *id);
+            }
+            Ok(*id)
+        }
+    }
+}
+
+impl<S: Storage> MessageRepository<S> {
+    /// Builds the repository and its indexes on the maps of `storage`, such as a
+    /// [`HeapStorage`] in tests.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            conversation_index: MessageConversationIndexRepository::with_storage(storage),
+            reply_index: MessageReplyIndexRepository::with_storage(storage),
+            term_index: MessageTermIndexRepository::with_storage(storage),
+            timestamp_index: MessageTimestampIndexRepository::with_storage(storage),
+            tombstones: MessageTombstoneRepository::with_storage(storage),
+            messages: storage.messages(),
+            tokens: storage.message_tokens(),
+            conversations: storage.conversation_map(),
+            user_counts: storage.user_message_counts(),
+            generator: storage.message_generator(),
+        }
+    }
+
+    /// Counts every message stored in the canister.
+    pub fn count(&self) -> u64 {
+        self.messages.len()
+    }
+
+    /// Counts the messages held by the conversations of a user, trashed ones included.
+    pub fn count_by_user(&self, user: UserId) -> u64 {
+        self.user_counts.get(&user).unwrap_or_default()
+    }
+
+    /// Moves a message into another conversation along with its indexes. The message it
+    /// replied to stays behind, so `reply_to` is cleared.
+    pub fn move_to(
+        &self,
+        id: MessageId,
+        conversation: ConversationId,
+    ) -> RepositoryResult<Message> {
+        let old = self.get_or_err(&id)?;
+        if !self.conversations.contains_key(&conversation) {
+            return Err(RepositoryError::NotFound);
+        }
+        let moved = Message {
+            conversation,
+            reply_to: None,
+            ..old.clone()
+        };
+        self.messages.insert(Reverse(id), moved.clone());
+        self.save_indexes(&moved, Some(&old));
+        Ok(moved)
+    }
+
+    /// Reports the gaps left in the message ids by deletes.
+    pub fn id_gaps(&self) -> GapReport {
+        self.messages
+            .walk(.., |entries| gap_report(entries.rev().map(|(k, _)| k.0)))
+    }
+
+    /// Retrieves a paginated list of messages for a conversation, newest first.
+    ///
+    /// The cursor is the id of the last message scanned, the next page starts right below it.
+    /// Ids never move, so messages deleted between two pages only make a page shorter, no
+    /// remaining message is skipped or repeated. Index entries whose message is missing still
+    /// advance the cursor, a page made only of those does not end the scroll.
+    pub fn paged_list(
+        &self,
+        conversation: ConversationId,
+        cursor: Option<MessageId>,
+        limit: usize,
+    ) -> (Option<MessageId>, Vec<Message>) {
+        let ids = self.conversation_index.find(conversation, cursor, limit);
+        let next = ids.last().copied();
+        (next, self.conversation_index.resolve(ids))
+    }
+
+    /// Counts the messages of a conversation newer than `after`, without loading them.
+    pub fn count_since(&self, conversation: ConversationId, after: MessageId) -> u64 {
+        if after == MessageId::MAX {
+            return 0;
+        }
+        let start = (conversation, Reverse(MessageId::MAX));
+        let end = (conversation, Reverse(after + 1));
+        self.conversation_index
+            .index
+            .walk(start..=end, |entries| entries.count() as u64)
+    }
+
+    /// Retrieves the oldest message of a conversation from the tail of the conversation index,
+    /// `None` when it has no message.
+    pub fn first_message(&self, conversation: ConversationId) -> Option<Message> {
+        let start = (conversation, Reverse(MessageId::MAX));
+        let end = (conversation, Reverse(1));
+        self.conversation_index.index.walk(start..=end, |entries| {
+            entries
+                .rev()
+                .find_map(|((_, id), _)| self.messages.get(&id))
+        })
+    }
+
+    /// Retrieves the messages of every conversation whose ids fall between `from` and `to`
+    /// inclusive, oldest first, at most `limit` of them (0 reads them all).
+    pub fn by_id_range(&self, from: MessageId, to: MessageId, limit: usize) -> Vec<Message> {
+        self.messages.walk(Reverse(to)..=Reverse(from), |entries| {
+            let values = entries.rev().map(|(_, v)| v);
+            if limit == usize::default() {
+                values.collect()
+            } else {
+                values.take(limit).collect()
+            }
+        })
+    }
+
+    /// Retrieves `limit` messages of a conversation after skipping the `offset` newest ones,
+    /// newest first (0 reads them all).
+    pub fn offset_list(
+        &self,
+        conversation: ConversationId,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<Message> {
+        let start = (conversation, Reverse(MessageId::MAX));
+        let end = (conversation, Reverse(1));
+        let ids = self.conversation_index.index.walk(start..=end, |entries| {
+            let ids = entries.skip(offset).map(|((_, id), _)| id.0);
+            if limit == usize::default() {
+                ids.collect_vec()
+            } else {
+                ids.take(limit).collect_vec()
+            }
+        });
+        self.conversation_index.resolve(ids)
+    }
+
+    /// Retrieves the `page`-th page of a conversation, pages counted from 0 and newest first.
+    /// The page size is clamped between 1 and the `max_page_size` setting, a page past the
+    /// last message is empty.
+    pub fn message_page(
+        &self,
+        conversation: ConversationId,
+        page: usize,
+        page_size: usize,
+    ) -> Vec<Message> {
+        let max = settings::get().max_page_size as usize;
+        let page_size = page_size.clamp(1, max.max(1));
+        self.offset_list(conversation, page.saturating_mul(page_size), page_size)
+    }
+
+    /// Retrieves the pivot message with up to `before` older and `after` newer messages of
+    /// the same conversation around it, newest first. The window is clamped at the
+    /// conversation boundaries, a zero count takes nothing on that side.
+    /// Returns NotFound when the pivot is not part of the conversation.
+    pub fn messages_around(
+        &self,
+        conversation: ConversationId,
+        pivot: MessageId,
+        before: usize,
+        after: usize,
+    ) -> RepositoryResult<Vec<Message>> {
+        if !self
+            .conversation_index
+            .exists(&(conversation, Reverse(pivot)))
+        {
+            return Err(RepositoryError::NotFound);
+        }
+        let start = (conversation, Reverse(MessageId::MAX));
+        let end = (conversation, Reverse(pivot));
+        // read from the pivot up, only as far as `after` entries
+        let mut newer = self.conversation_index.index.walk(start..end, |entries| {
+            entries
+                .rev()
+                .take(after)
+                .map(|((_, id), _)| id.0)
+                .collect_vec()
+        });
+        newer.reverse();
+        let older = if before == usize::default() {
+            vec![]
+        } else {
+            self.conversation_index
+                .find(conversation, Some(pivot), before)
+        };
+        let ids = newer
+            .into_iter()
+            .chain(std::iter::once(pivot))
+            .chain(older)
+            .collect_vec();
+        Ok(self.conversation_index.resolve(ids))
+    }
+
+    /// Searches the messages of a conversation holding every term of the query, in the given
+    /// order. At most `limit` messages are returned, 0 returns them all. Ranking by relevance
+    /// scores every match before the limit applies.
+    pub fn search_messages(
+        &self,
+        conversation: ConversationId,
+        query: &str,
+        limit: usize,
+        order: SearchOrder,
+    ) -> Vec<Message> {
+        let query = terms(query).unique().collect_vec();
+        let ids = self.matching_ids(conversation, &query, None);
+        if order == SearchOrder::Relevance {
+            let ranked = self
+                .term_index
+                .resolve(ids.collect_vec())
+                .into_iter()
+                .enumerate()
+                .map(|(newer, message)| (relevance(&message, &query, newer), message))
+                .sorted_by_key(|(score, _)| Reverse(*score))
+                .map(|(_, message)| message);
+            return if limit == usize::default() {
+                ranked.collect_vec()
+            } else {
+                ranked.take(limit).collect_vec()
+            };
+        }
+        let ids = if limit == usize::default() {
+            ids.collect_vec()
+        } else {
+            ids.take(limit).collect_vec()
+        };
+        self.term_index.resolve(ids)
+    }
+
+    /// Retrieves a page of the messages of a conversation holding every term of the query,
+    /// newest first. The cursor is the id of the last match returned, the next page starts
+    /// right below it. Matches inserted between two pages are newer than the cursor, they never
+    /// shift the following pages.
+    pub fn search_page(
+        &self,
+        conversation: ConversationId,
+        query: &str,
+        cursor: Option<MessageId>,
+        limit: usize,
+    ) -> (Option<MessageId>, Vec<Message>) {
+        let query = terms(query).unique().collect_vec();
+        let ids = self.matching_ids(conversation, &query, cursor);
+        let ids = if limit == usize::default() {
+            ids.collect_vec()
+        } else {
+            ids.take(limit).collect_vec()
+        };
+        let next = ids.last().copied();
+        (next, self.term_index.resolve(ids))
+    }
+
+    /// Ids of the messages of a conversation older than `cursor` holding every term of the
+    /// query, newest first. An empty query matches nothing.
+    fn matching_ids<'a>(
+        &'a self,
+        conversation: ConversationId,
+        query: &'a [String],
+        cursor: Option<MessageId>,
+    ) -> impl Iterator<Item = MessageId> + 'a {
+        let (first, rest) = query
+            .split_first()
+            .map_or((None, &[][..]), |(first, rest)| (Some(first), rest));
+        first
+            .into_iter()
+            .flat_map(move |first| {
+                self.term_index
+                    .find((first.clone(), conversation), cursor, 0)
+            })
+            .filter(move |id| {
+                rest.iter().all(|term| {
+                    self.term_index.exists(&MessageTerm {
+                        term: term.clone(),
+                        conversation,
+                        message: *id,
+                    })
+                })
+            })
+    }
+
+    /// Retrieves a page of the messages of every conversation, newest first.
+    /// The cursor is the insertion time and id of the last message scanned.
+    pub fn recent(
+        &self,
+        cursor: Option<(Timestamp, MessageId)>,
+        limit: usize,
+    ) -> (Option<(Timestamp, MessageId)>, Vec<Message>) {
+        let ids = self
+            .timestamp_index
+            .find(Timestamp::default(), cursor, limit);
+        let next = ids.last().copied();
+        (next, self.timestamp_index.resolve(ids))
+    }
+
+    /// Retrieves the replies of a message, newest first.
+    pub fn replies(&self, message_id: MessageId) -> Vec<Message> {
+        self.reply_index.find_values(message_id, None, 0)
+    }
+
+    /// Deletes the messages of a conversation, newest first, at most `limit` of them per call
+    /// (0 deletes them all). Call again while more messages remain.
+    /// Returns the deleted ids, the ids that failed to be deleted, and whether more remain.
+    /// Failures are logged and their index entries dropped, so the next call does not stop on them.
+    pub fn delete_by_conversation(
+        &self,
+        conversation: &ConversationId,
+        limit: usize,
+    ) -> RepositoryResult<(Vec<MessageId>, Vec<MessageId>, bool)> {
+        let (deleted, failed): (Vec<_>, Vec<_>) = self
+            .conversation_index
+            .find(*conversation, None, limit)
+            .into_iter()
+            .partition_map(|id| match self.delete(&id) {
+                Ok(id) => itertools::Either::Left(id),
+                Err(e) => {
+                    log(&format!(
+                        "failed to delete message {} of conversation {}: {}",
+                        id, conversation, e
+                    ));
+                    self.conversation_index
+                        .remove(&(*conversation, Reverse(id)));
+                    itertools::Either::Right(id)
+                }
+            });
+        let more = !self
+            .conversation_index
+            .find(*conversation, None, 1)
+            .is_empty();
+        Ok((deleted, failed, more))
+    }
+}
+
+#[derive(Debug)]
+pub struct ConversationUserIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<ConversationIndex, ()>,
+    conversations: S::Map<ConversationId, Conversation>,
+}
+
+#[derive(Debug)]
+pub struct ConversationCreatedIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<ConversationIndex, ()>,
+    conversations: S::Map<ConversationId, Conversation>,
+}
+
+impl<S: Storage> ConversationCreatedIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.conversation_created_index(),
+            conversations: storage.conversation_map(),
+        }
+    }
+}
+
+impl<S: Storage> ConversationUserIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.conversation_user_index(),
+            conversations: storage.conversation_map(),
+        }
+    }
+
+    /// Finds the conversations of a user in the given direction, the cursor being the update
+    /// timestamp of the last seen conversation.
+    pub fn find_sorted(
+        &self,
+        user_id: UserId,
+        cursor: Option<Timestamp>,
+        limit: usize,
+        dir: SortDir,
+    ) -> Vec<ConversationId> {
+        let (newest, oldest) = match (dir, cursor) {
+            (_, None) => (Timestamp::MAX, 0),
+            (SortDir::Desc, Some(ts)) => (ts.saturating_sub(1), 0),
+            (SortDir::Asc, Some(ts)) => (Timestamp::MAX, ts.saturating_add(1)),
+        };
+        let start = (user_id, Reverse(newest), 0);
+        let end = (user_id, Reverse(oldest), ConversationId::MAX);
+
+        self.index.walk(start..=end, |entries| {
+            let ids = entries.map(|((_, _, c_id), _)| c_id);
+            match dir {
+                SortDir::Desc if limit == usize::default() => ids.collect(),
+                SortDir::Desc => ids.take(limit).collect(),
+                SortDir::Asc => {
+                    let mut ids = ids.collect_vec();
+                    ids.reverse();
+                    if limit != usize::default() {
+                        ids.truncate(limit);
+                    }
+                    ids
+                }
+            }
+        })
+    }
+}
+
+/// Conversations of every user, least recently updated first.
+#[derive(Debug)]
+pub struct ConversationStaleIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<(Timestamp, ConversationId), ()>,
+}
+
+/// Trashed conversations of a user, most recently trashed first.
+#[derive(Debug)]
+pub struct ConversationTrashIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<ConversationIndex, ()>,
+    conversations: S::Map<ConversationId, Conversation>,
+}
+
+/// Deleted conversations of each user, so that delta syncs can tell clients to drop them.
+#[derive(Debug)]
+pub struct ConversationTombstoneRepository<S: Storage = StableStorage> {
+    tombstones: S::Map<Tombstone, ()>,
+}
+
+impl<S: Storage> ConversationStaleIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.conversation_stale_index(),
+        }
+    }
+}
+
+impl<S: Storage> ConversationTombstoneRepository<S> {
+    /// Builds the repository on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            tombstones: storage.conversation_tombstones(),
+        }
+    }
+
+    /// Records that a conversation of `user` was deleted now.
+    pub fn record(&self, user: UserId, conversation: ConversationId) {
+        record_tombstone(&self.tombstones, user, conversation);
+    }
+
+    /// Finds the conversations of `user` deleted at `since` or later, oldest delete first.
+    pub fn since(&self, user: UserId, since: Timestamp) -> Vec<ConversationId> {
+        tombstones_since(&self.tombstones, user, since)
+    }
+
+    /// Forgets the conversations of every user deleted before `before`. Returns how many were
+    /// pruned.
+    pub fn prune_before(&self, before: Timestamp) -> u64 {
+        prune_tombstones(&self.tombstones, before)
+    }
+}
+
+/// Receives the lifecycle events of the conversations, to wire analytics without coupling the
+/// repository to them.
+pub trait ConversationEventSink: Debug + Send + Sync {
+    /// Called once a new conversation is stored.
+    fn on_created(&self, conversation: &Conversation);
+}
+
+/// [`ConversationEventSink`] ignoring every event.
+#[derive(Default, Debug)]
+pub struct NoopEventSink;
+
+impl ConversationEventSink for NoopEventSink {
+    fn on_created(&self, _conversation: &Conversation) {}
+}
+
+/// Trashed conversations are only kept in the trash index, out of every other index.
+#[derive(Debug)]
+pub struct ConversationRepository<S: Storage = StableStorage> {
+    pub user_index: ConversationUserIndexRepository<S>,
+    pub created_index: ConversationCreatedIndexRepository<S>,
+    pub stale_index: ConversationStaleIndexRepository<S>,
+    pub trash_index: ConversationTrashIndexRepository<S>,
+    pub tombstones: ConversationTombstoneRepository<S>,
+    tags: ConversationTagRepository<S>,
+    message_index: MessageConversationIndexRepository<S>,
+    conversations: S::Conversations,
+    /// The conversations past the cache, for the scans which would otherwise evict the
+    /// conversations in use from it.
+    conversation_map: S::Map<ConversationId, Conversation>,
+    /// Pinned conversations of each user, trashed ones included since a restore brings them
+    /// back pinned. Kept along with the indexes.
+    pinned_counts: S::Map<UserId, u64>,
+    generator: S::Generator,
+    event_sink: Arc<dyn ConversationEventSink>,
+}
+
+impl Default for ConversationRepository {
+    fn default() -> Self {
+        Self::with_storage(&StableStorage)
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<(Timestamp, ConversationId), ConversationId>
+    for ConversationStaleIndexRepository<S>
+{
+    /// Conversations updated before this time are found.
+    type Criteria = Timestamp;
+    type Cursor = (Timestamp, ConversationId);
+
+    fn exists(&self, index: &(Timestamp, ConversationId)) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: (Timestamp, ConversationId)) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &(Timestamp, ConversationId)) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        older_than: Self::Criteria,
+        cursor: Option<Self::Cursor>,
+        limit: usize,
+    ) -> Vec<ConversationId> {
+        let start = cursor.map_or((0, 0), |(ts, id)| (ts, id + 1));
+        let end = (older_than, 0);
+        if start >= end {
+            return vec![];
+        }
+
+        if limit == usize::default() {
+            self.index.walk(start..end, |entries| {
+                entries.map(|((_, c_id), _)| c_id).collect()
+            })
+        } else {
+            self.index.walk(start..end, |entries| {
+                entries.take(limit).map(|((_, c_id), _)| c_id).collect()
+            })
+        }
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<ConversationIndex, ConversationId>
+    for ConversationUserIndexRepository<S>
+{
+    type Criteria = UserId;
+    type Cursor = Timestamp;
+
+    fn exists(&self, index: &ConversationIndex) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: ConversationIndex) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &ConversationIndex) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        user_id: Self::Criteria,
+        cursor: Option<Timestamp>,
+        limit: usize,
+    ) -> Vec<ConversationId> {
+        self.find_sorted(user_id, cursor, limit, SortDir::Desc)
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<ConversationIndex, ConversationId>
+    for ConversationCreatedIndexRepository<S>
+{
+    type Criteria = UserId;
+    type Cursor = Timestamp;
+
+    fn exists(&self, index: &ConversationIndex) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: ConversationIndex) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &ConversationIndex) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        user_id: Self::Criteria,
+        cursor: Option<Timestamp>,
+        limit: usize,
+    ) -> Vec<ConversationId> {
+        let ts = cursor.map_or(Timestamp::MAX, |ts| ts.saturating_sub(1));
+        let start = (user_id, Reverse(ts), 0);
+        let end = (user_id, Reverse(0), ConversationId::MAX);
+
+        if limit == usize::default() {
+            self.index.walk(start..=end, |entries| {
+                entries.map(|((_, _, c_id), _)| c_id).collect()
+            })
+        } else {
+            self.index.walk(start..=end, |entries| {
+                entries.take(limit).map(|((_, _, c_id), _)| c_id).collect()
+            })
+        }
+    }
+}
+
+impl<S: Storage> IndexManagementRepository<ConversationIndex, ConversationId>
+    for ConversationTrashIndexRepository<S>
+{
+    type Criteria = UserId;
+    type Cursor = Timestamp;
+
+    fn exists(&self, index: &ConversationIndex) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: ConversationIndex) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &ConversationIndex) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        user_id: Self::Criteria,
+        cursor: Option<Timestamp>,
+        limit: usize,
+    ) -> Vec<ConversationId> {
+        let ts = cursor.map_or(Timestamp::MAX, |ts| ts.saturating_sub(1));
+        let start = (user_id, Reverse(ts), 0);
+        let end = (user_id, Reverse(0), ConversationId::MAX);
+
+        if limit == usize::default() {
+            self.index.walk(start..=end, |entries| {
+                entries.map(|((_, _, c_id), _)| c_id).collect()
+            })
+        } else {
+            self.index.walk(start..=end, |entries| {
+                entries.take(limit).map(|((_, _, c_id), _)| c_id).collect()
+            })
+        }
+    }
+}
+
+impl<S: Storage> ConversationTrashIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.conversation_trash_index(),
+            conversations: storage.conversation_map(),
+        }
+    }
+
+    /// Finds the conversations of every user trashed before `cutoff`.
+    pub fn find_trashed_before(&self, cutoff: Timestamp) -> Vec<ConversationId> {
+        self.index.walk(.., |entries| {
+            entries
+                .filter(|((_, Reverse(deleted_at), _), _)| *deleted_at < cutoff)
+                .map(|((_, _, c_id), _)| c_id)
+                .collect()
+        })
+    }
+
+    /// Finds the conversations of a user trashed at `since` or later, most recently trashed
+    /// first.
+    pub fn trashed_since(&self, user: UserId, since: Timestamp) -> Vec<ConversationId> {
+        let start = (user, Reverse(Timestamp::MAX), 0);
+        let end = (user, Reverse(since), ConversationId::MAX);
+        self.index.walk(start..=end, |entries| {
+            entries.map(|((_, _, c_id), _)| c_id).collect()
+        })
+    }
+}
+
+impl<S: Storage> IndexValueRepository<ConversationIndex, ConversationId>
+    for ConversationTrashIndexRepository<S>
+{
+    type Value = Conversation;
+
+    fn resolve(&self, ids: Vec<ConversationId>) -> Vec<Conversation> {
+        resolve_conversations(&self.conversations, ids)
+    }
+}
+
+impl<S: Storage> IndexValueRepository<ConversationIndex, ConversationId>
+    for ConversationUserIndexRepository<S>
+{
+    type Value = Conversation;
+
+    fn resolve(&self, ids: Vec<ConversationId>) -> Vec<Conversation> {
+        resolve_conversations(&self.conversations, ids)
+    }
+}
+
+impl<S: Storage> IndexValueRepository<ConversationIndex, ConversationId>
+    for ConversationCreatedIndexRepository<S>
+{
+    type Value = Conversation;
+
+    fn resolve(&self, ids: Vec<ConversationId>) -> Vec<Conversation> {
+        resolve_conversations(&self.conversations, ids)
+    }
+}
+
+impl<S: Storage> IndexedRepository<Conversation> for ConversationRepository<S> {
+    fn remove_indexes(&self, conv: &Conversation) {
+        if conv.pinned {
+            match self.pinned_count(conv.user) {
+                0 | 1 => self.pinned_counts.remove(&conv.user),
+                count => self.pinned_counts.insert(conv.user, count - 1),
+            };
+        }
+        if let Some(deleted_at) = conv.deleted_at {
+            self.trash_index
+                .remove(&(conv.user, Reverse(deleted_at), conv.id));
+            return;
+        }
+        self.user_index
+            .remove(&(conv.user, Reverse(conv.updated_at), conv.id));
+        self.created_index
+            .remove(&(conv.user, Reverse(conv.created_at), conv.id));
+        self.stale_index.remove(&(conv.updated_at, conv.id));
+    }
+
+    fn add_indexes(&self, conv: &Conversation) {
+        if conv.pinned {
+            let count = self.pinned_count(conv.user);
+            self.pinned_counts.insert(conv.user, count + 1);
+        }
+        if let Some(deleted_at) = conv.deleted_at {
+            self.trash_index
+                .insert((conv.user, Reverse(deleted_at), conv.id));
+            return;
+        }
+        self.user_index
+            .insert((conv.user, Reverse(conv.updated_at), conv.id));
+        self.created_index
+            .insert((conv.user, Reverse(conv.created_at), conv.id));
+        self.stale_index.insert((conv.updated_at, conv.id));
+    }
+
+    fn clear_indexes(&self) {
+        self.user_index.clear();
+        self.created_index.clear();
+        self.stale_index.clear();
+        self.trash_index.clear();
+        self.pinned_counts.clear();
+    }
+
+    fn slice(&self, from: u64, limit: usize) -> Vec<(u64, Conversation)> {
+        slice_from(&self.conversations, from, limit)
+    }
+}
+
+impl<S: Storage> SerialIdRepository for ConversationRepository<S> {
+    type Generator = S::Generator;
+
+    fn generator(&self) -> S::Generator {
+        self.generator.clone()
+    }
+}
+
+/// Rejects a conversation name holding a blocked word, when enabled in the settings.
+fn check_conversation_name(name: &str) -> RepositoryResult<()> {
+    let settings = settings::get();
+    let filter = BlocklistFilter {
+        words: settings.blocked_words,
+    };
+    if settings.filter_conversation_names && !filter.allows(name) {
+        return Err(RepositoryError::InvalidData {
+            reason: "conversation name is not allowed".to_string(),
+        });
+    }
+    Ok(())
+}
+
+impl<S: Storage> Repository<ConversationId, Conversation> for ConversationRepository<S> {
+    /// Retrieves a conversation by its ID, from the cache when it was fetched recently.
+    fn get(&self, id: &ConversationId) -> Option<Conversation> {
+        self.conversations.get(id)
+    }
+
+    /// Inserts a new conversation into the repository, the name must pass the name filter.
+    /// The event sink is notified once it is stored.
+    fn insert(&self, mut conversation: Conversation) -> RepositoryResult<Conversation> {
+        check_conversation_name(&conversation.name)?;
+        conversation.id = self.next_id();
+        conversation.updated_at = timestamp();
+        conversation.created_at = conversation.updated_at;
+        let prev = self
+            .conversations
+            .insert(conversation.id, conversation.clone());
+        self.save_indexes(&conversation, prev.as_ref());
+        self.event_sink.on_created(&conversation);
+
+        Ok(conversation)
+    }
+
+    /// Update the conversation on the repository.
+    /// The creation time is kept from the stored record, and the owner cannot be changed.
+    /// A new name must pass the name filter.
+    fn update(&self, mut conversation: Conversation) -> RepositoryResult<Conversation> {
+        if let Some(old_conv) = self.get(&conversation.id) {
+            if old_conv.user != conversation.user {
+                return Err(RepositoryError::OwnershipMismatch {
+                    id: conversation.id,
+                });
+            }
+            if old_conv.name != conversation.name {
+                check_conversation_name(&conversation.name)?;
+            }
+            conversation.created_at = old_conv.created_at;
+        } else {
+            return Err(RepositoryError::NotFound);
+        }
+        conversation.updated_at = timestamp();
+        let prev = self
+            .conversations
+            .insert(conversation.id, conversation.clone());
+        self.save_indexes(&conversation, prev.as_ref());
+
+        Ok(conversation)
+    }
+
+    /// Deletes a conversation along with its indexes and tags, and leaves a tombstone for its
+    /// owner.
+    fn delete(&self, id: &ConversationId) -> RepositoryResult<ConversationId> {
+        let old = self.conversations.remove(id);
+        if old.is_none() {
+            Err(RepositoryError::NotFound)
+        } else {
+            let old = old.unwrap();
+            self.remove_indexes(&old);
+            self.tags.remove_all(old.user, *id);
+            self.tombstones.record(old.user, *id);
+            Ok(*id)
+        }
+    }
+}
+
+impl<S: Storage> ConversationRepository<S> {
+    /// Builds the repository, its indexes and tags on the maps of `storage`, such as a
+    /// [`HeapStorage`] in tests. No sink is notified of the events.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            user_index: ConversationUserIndexRepository::with_storage(storage),
+            created_index: ConversationCreatedIndexRepository::with_storage(storage),
+            stale_index: ConversationStaleIndexRepository::with_storage(storage),
+            trash_index: ConversationTrashIndexRepository::with_storage(storage),
+            tombstones: ConversationTombstoneRepository::with_storage(storage),
+            tags: ConversationTagRepository::with_storage(storage),
+            message_index: MessageConversationIndexRepository::with_storage(storage),
+            conversations: storage.conversations(),
+            conversation_map: storage.conversation_map(),
+            pinned_counts: storage.user_pinned_counts(),
+            generator: storage.conversation_generator(),
+            event_sink: Arc::new(NoopEventSink),
+        }
+    }
+
+    /// Reports the gaps left in the conversation ids by deletes.
+    pub fn id_gaps(&self) -> GapReport {
+        self.conversations
+            .walk(.., |entries| gap_report(entries.map(|(k, _)| k)))
+    }
+
+    /// Notifies `sink` of the events of the conversations written through this repository.
+    pub fn with_event_sink(mut self, sink: Arc<dyn ConversationEventSink>) -> Self {
+        self.event_sink = sink;
+        self
+    }
+
+    /// Stores a conversation as is, keeping its update time.
+    fn store(&self, conversation: Conversation) -> Conversation {
+        let prev = self
+            .conversations
+            .insert(conversation.id, conversation.clone());
+        self.save_indexes(&conversation, prev.as_ref());
+        conversation
+    }
+
+    /// Renames a conversation, without counting it as an update. The name must pass the name
+    /// filter.
+    pub fn rename(&self, id: ConversationId, name: String) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        check_conversation_name(&name)?;
+        Ok(self.store(Conversation {
+            name,
+            ..conversation
+        }))
+    }
+
+    /// Archives or unarchives a conversation, without counting it as an update.
+    pub fn set_archived(
+        &self,
+        id: ConversationId,
+        archived: bool,
+    ) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        Ok(self.store(Conversation {
+            archived,
+            ..conversation
+        }))
+    }
+
+    /// Moves a conversation to the trash, out of the lists of its user, without counting it as
+    /// an update. Its messages are kept until it is purged.
+    pub fn trash(&self, id: ConversationId) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        if conversation.deleted_at.is_some() {
+            return Ok(conversation);
+        }
+        Ok(self.store(Conversation {
+            deleted_at: Some(timestamp()),
+            ..conversation
+        }))
+    }
+
+    /// Takes a conversation out of the trash, back into the lists of its user.
+    pub fn restore(&self, id: ConversationId) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        if conversation.deleted_at.is_none() {
+            return Ok(conversation);
+        }
+        Ok(self.store(Conversation {
+            deleted_at: None,
+            ..conversation
+        }))
+    }
+
+    /// Pins or unpins a conversation, without counting it as an update.
+    /// A user pins at most `max_pinned` conversations of the settings, unpinning frees a slot.
+    /// A trashed conversation keeps its pin and its slot until it is deleted for good.
+    pub fn set_pinned(&self, id: ConversationId, pinned: bool) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        let limit = settings::get().max_pinned;
+        if pinned
+            && !conversation.pinned
+            && limit != 0
+            && self.pinned_count(conversation.user) >= limit
+        {
+            return Err(RepositoryError::QuotaExceeded { limit });
+        }
+        Ok(self.store(Conversation {
+            pinned,
+            ..conversation
+        }))
+    }
+
+    /// Stars or unstars a conversation, without counting it as an update.
+    pub fn set_starred(&self, id: ConversationId, starred: bool) -> RepositoryResult<Conversation> {
+        let conversation = self.get_or_err(&id)?;
+        if conversation.starred == starred {
+            return Ok(conversation);
+        }
+        Ok(self.store(Conversation {
+            starred,
+            ..conversation
+        }))
+    }
+
+    /// Archives the conversations of every user not updated since `older_than`.
+    /// Pinned and already archived conversations are left alone. Returns the archived ids.
+    /// The conversations are read past the cache.
+    pub fn archive_inactive(&self, older_than: Timestamp) -> Vec<ConversationId> {
+        self.stale_index
+            .find(older_than, None, 0)
+            .into_iter()
+            .filter_map(|id| self.conversation_map.get(&id))
+            .filter(|c| !c.archived && !c.pinned)
+            .map(|c| {
+                self.store(Conversation {
+                    archived: true,
+                    ..c
+                })
+                .id
+            })
+            .collect()
+    }
+
+    /// Creates a new conversation owned by `user`.
+    pub fn create(&self, name: String, user: UserId) -> RepositoryResult<Conversation> {
+        self.insert(Conversation::builder(user).name(name).build())
+    }
+
+    /// Inserts or updates a conversation in the repository.
+    ///
+    /// A conversation with [`NEW_ENTITY_ID`] is always created. A conversation with an id that is
+    /// already stored is updated. Any other id is not kept: the conversation is created under a
+    /// freshly generated id, use the returned conversation to know it. The outcome also tells
+    /// whether the conversation was created or updated.
+    pub fn upsert(&self, conversation: Conversation) -> RepositoryResult<UpsertOutcome> {
+        let created = conversation.id == NEW_ENTITY_ID || self.get(&conversation.id).is_none();
+        let conversation = if created {
+            self.insert(conversation)?
+        } else {
+            self.update(conversation)?
+        };
+        Ok(UpsertOutcome {
+            conversation,
+            created,
+        })
+    }
+
+    /// Retrieves a paginated list of conversations for a user, in the order of their last update.
+    /// Cursor is the update timestamp of the last seen conversation instead of its id.
+    ///
+    /// Conversations without messages are skipped unless `include_empty`, the page is then
+    /// filled from further down the list and the cursor points at the last conversation scanned,
+    /// so that skipped conversations are not scanned again.
+    pub fn paged_list(
+        &self,
+        user_id: UserId,
+        cursor: Option<Timestamp>,
+        limit: usize,
+        dir: SortDir,
+        include_empty: bool,
+    ) -> (Option<Timestamp>, Vec<Conversation>) {
+        if include_empty {
+            let ids = self.user_index.find_sorted(user_id, cursor, limit, dir);
+            let conv = self.user_index.resolve(ids);
+            return (conv.last().map(|c| c.updated_at), conv);
+        }
+
+        let mut page = Vec::new();
+        let mut last_scanned = None;
+        loop {
+            let ids = self
+                .user_index
+                .find_sorted(user_id, last_scanned.or(cursor), limit, dir);
+            let scanned = self.user_index.resolve(ids);
+            let Some(last) = scanned.last() else {
+                break;
+            };
+            last_scanned = Some(last.updated_at);
+            for conv in scanned {
+                if self.message_index.find(conv.id, None, 1).is_empty() {
+                    continue;
+                }
+                let updated_at = conv.updated_at;
+                page.push(conv);
+                if page.len() == limit {
+                    return (Some(updated_at), page);
+                }
+            }
+            if limit == usize::default() {
+                break;
+            }
+        }
+        (last_scanned, page)
+    }
+
+    /// Retrieves the conversations of a user updated at `since` or later, newest first.
+    pub fn updated_since(&self, user_id: UserId, since: Timestamp) -> Vec<Conversation> {
+        let start = (user_id, Reverse(Timestamp::MAX), 0);
+        let end = (user_id, Reverse(since), ConversationId::MAX);
+        let ids = self.user_index.index.walk(start..=end, |entries| {
+            entries.map(|((_, _, id), _)| id).collect_vec()
+        });
+        self.user_index.resolve(ids)
+    }
+
+    /// Finds the conversation of a user named exactly `name`, the most recently updated one
+    /// when several share it. The user index is scanned, so renames and trashed conversations
+    /// are accounted for without an index of their own. The conversations are read past the
+    /// cache.
+    pub fn find_by_exact_name(&self, user_id: UserId, name: &str) -> Option<Conversation> {
+        let start = (user_id, Reverse(Timestamp::MAX), 0);
+        let end = (user_id, Reverse(0), ConversationId::MAX);
+        self.user_index.index.walk(start..=end, |entries| {
+            entries
+                .filter_map(|((_, _, id), _)| self.conversation_map.get(&id))
+                .find(|c| c.name == name)
+        })
+    }
+
+    /// Retrieves the most recently updated conversation of a user that is not archived, reading
+    /// the user index from its newest entry until one qualifies, past the cache.
+    pub fn most_recent_conversation(&self, user_id: UserId) -> Option<Conversation> {
+        let start = (user_id, Reverse(Timestamp::MAX), 0);
+        let end = (user_id, Reverse(0), ConversationId::MAX);
+        self.user_index.index.walk(start..=end, |entries| {
+            entries
+                .filter_map(|((_, _, id), _)| {
+                    self.conversation_map.get(&id).filter(|c| !c.archived)
+                })
+                .next()
+        })
+    }
+
+    /// Counts the pinned conversations of a user, trashed ones included.
+    pub fn pinned_count(&self, user: UserId) -> u64 {
+        self.pinned_counts.get(&user).unwrap_or_default()
+    }
+
+    /// Counts the conversations of a user without loading them.
+    pub fn count_by_user(&self, user_id: UserId) -> u64 {
+        let start = (user_id, Reverse(Timestamp::MAX), 0);
+        let end = (user_id, Reverse(0), ConversationId::MAX);
+        self.user_index
+            .index
+            .walk(start..=end, |entries| entries.count() as u64)
+    }
+
+    /// Retrieves a paginated list of conversations for a user in creation order, newest first.
+    /// Cursor is the creation timestamp of the last seen conversation.
+    pub fn paged_list_by_created(
+        &self,
+        user_id: UserId,
+        cursor: Option<Timestamp>,
+        limit: usize,
+    ) -> (Option<Timestamp>, Vec<Conversation>) {
+        let conv = self.created_index.find_values(user_id, cursor, limit);
+        (conv.last().map(|c| c.created_at), conv)
+    }
+}
+
+#[derive(Debug)]
+pub struct UserIdentityIndexRepository<S: Storage = StableStorage> {
+    index: S::Map<(Principal, UserId), ()>,
+}
+
+#[derive(Debug)]
+pub struct UserRepository<S: Storage = StableStorage> {
+    identity_index: UserIdentityIndexRepository<S>,
+    users: S::Map<UserId, User>,
+    generator: S::Generator,
+}
+
+impl Default for UserRepository {
+    fn default() -> Self {
+        Self::with_storage(&StableStorage)
+    }
+}
+
+impl<S: Storage> UserIdentityIndexRepository<S> {
+    /// Builds the index on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            index: storage.user_identity_index(),
+        }
+    }
+}
+
+/// Maps a caller identity to its user, see `IcvCtx::resolve`.
+pub trait IdentityProvider {
+    fn get_user(&self, identity: Principal) -> Option<User>;
+}
+
+impl<S: Storage> IndexManagementRepository<(Principal, UserId), UserId>
+    for UserIdentityIndexRepository<S>
+{
+    type Criteria = Principal;
+    type Cursor = UserId;
+
+    fn exists(&self, index: &(Principal, UserId)) -> bool {
+        self.index.contains_key(index)
+    }
+
+    fn insert(&self, index: (Principal, UserId)) {
+        self.index.insert(index, ());
+    }
+
+    fn remove(&self, index: &(Principal, UserId)) -> bool {
+        self.index.remove(index).is_some()
+    }
+
+    fn clear(&self) {
+        self.index.clear();
+    }
+
+    fn find(
+        &self,
+        principal: Self::Criteria,
+        _cursor: Option<Self::Cursor>,
+        _limit: usize,
+    ) -> Vec<UserId> {
+        let start = (principal, 1);
+        let end = (principal, UserId::MAX);
+        self.index.walk(start..=end, |entries| {
+            entries.map(|((_, id), _)| id).collect_vec()
+        })
+    }
+}
+
+impl<S: Storage> IndexedRepository<User> for UserRepository<S> {
+    fn remove_indexes(&self, value: &User) {
+        self.identity_index.remove(&(value.identity, value.id));
+    }
+
+    fn add_indexes(&self, value: &User) {
+        self.identity_index.insert((value.identity, value.id));
+    }
+
+    fn clear_indexes(&self) {
+        self.identity_index.clear();
+    }
+
+    fn slice(&self, from: u64, limit: usize) -> Vec<(u64, User)> {
+        slice_from(&self.users, from, limit)
+    }
+}
+
+impl<S: Storage> SerialIdRepository for UserRepository<S> {
+    type Generator = S::Generator;
+
+    fn generator(&self) -> S::Generator {
+        self.generator.clone()
+    }
+}
+
+/// Size of a resume once encoded, in bytes.
+fn resume_bytes(resume: &str) -> u64 {
+    let mut encoded = Vec::new();
+    ciborium::into_writer(resume, &mut encoded).unwrap();
+    encoded.len() as u64
+}
+
+/// Redacts the resume of a user when enabled in the settings, then rejects it when its
+/// encoded size is over the `max_resume_bytes` setting. The resume `previous` stored for the
+/// user may keep its size, so that a resume stored before the cap can still be edited as long
+/// as it does not grow.
+fn prepare_resume(user: &mut User, previous: Option<&User>) -> RepositoryResult<()> {
+    let settings = settings::get();
+    if settings.redact_resume {
+        user.resume = redact_resume(&user.resume);
+    }
+    let bytes = resume_bytes(&user.resume);
+    let allowed = previous.map_or(settings.max_resume_bytes, |p| {
+        settings.max_resume_bytes.max(resume_bytes(&p.resume))
+    });
+    if settings.max_resume_bytes != 0 && bytes > allowed {
+        return Err(RepositoryError::ResumeTooLarge {
+            bytes,
+            max: settings.max_resume_bytes,
+        });
+    }
+    Ok(())
+}
+
+impl<S: Storage> Repository<UserId, User> for UserRepository<S> {
+    fn get(&self, id: &UserId) -> Option<User> {
+        self.users.get(id)
+    }
+
+    /// Inserts a new user, the resume is redacted first when enabled in the settings and
+    /// rejected when over the size cap.
+    fn insert(&self, mut user: User) -> RepositoryResult<User> {
+        prepare_resume(&mut user, None)?;
+        user.id = self.next_id();
+        let prev = self.users.insert(user.id, user.clone());
+        self.save_indexes(&user, prev.as_ref());
+        Ok(user)
+    }
+
+    /// Updates an existing user, the resume is redacted first when enabled in the settings and
+    /// rejected when over the size cap, unless it is no larger than the stored one.
+    fn update(&self, mut user: User) -> RepositoryResult<User> {
+        let existing = self.get_or_err(&user.id)?;
+        prepare_resume(&mut user, Some(&existing))?;
+        let prev = self.users.insert(user.id, user.clone());
+        self.save_indexes(&user, prev.as_ref());
+        Ok(user)
+    }
+
+    fn delete(&self, id: &UserId) -> RepositoryResult<UserId> {
+        let old = self.users.remove(id);
+        if old.is_none() {
+            Err(RepositoryError::NotFound)
+        } else {
+            self.remove_indexes(&old.unwrap());
+            Ok(*id)
+        }
+    }
+}
+
+impl<S: Storage> IdentityProvider for UserRepository<S> {
+    fn get_user(&self, identity: Principal) -> Option<User> {
+        self.identity_index
+            .find(identity, None, 0)
+            .iter()
+            .filter_map(|id| self.get(id))
+            .last()
+    }
+}
+
+impl<S: Storage> UserRepository<S> {
+    /// Builds the repository and its index on the maps of `storage`, such as a [`HeapStorage`]
+    /// in tests.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            identity_index: UserIdentityIndexRepository::with_storage(storage),
+            users: storage.users(),
+            generator: storage.user_generator(),
+        }
+    }
+
+    /// Updates the user registered with `principal`, or registers a new one.
+    /// The id of an existing user is preserved.
+    pub fn upsert_by_principal(
+        &self,
+        principal: Principal,
+        fullname: String,
+        resume: String,
+    ) -> RepositoryResult<User> {
+        match self.get_user(principal) {
+            Some(user) => self.update(User {
+                fullname,
+                resume,
+                ..user
+            }),
+            None => self.insert(User {
+                id: 0,
+                fullname,
+                identity: principal,
+                resume,
+            }),
+        }
+    }
+
+    /// Reports the gaps left in the user ids by deletes.
+    pub fn id_gaps(&self) -> GapReport {
+        self.users
+            .walk(.., |entries| gap_report(entries.map(|(k, _)| k)))
+    }
+}
+
+/// Keeps the last message read by a user on each of their conversations.
+#[derive(Debug)]
+pub struct ReadMarkerRepository<S = StableStore<(UserId, ConversationId), MessageId>> {
+    store: S,
+}
+
+impl Default for ReadMarkerRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_READ)
+    }
+}
+
+impl<S: Store<(UserId, ConversationId), MessageId>> ReadMarkerRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the last message read by the user on a conversation.
+    pub fn get(&self, user: UserId, conversation: ConversationId) -> Option<MessageId> {
+        self.store.get(&(user, conversation))
+    }
+
+    /// Moves the read marker forward, an older message never moves it back.
+    pub fn mark(
+        &self,
+        user: UserId,
+        conversation: ConversationId,
+        message: MessageId,
+    ) -> MessageId {
+        let last_read = self
+            .store
+            .get(&(user, conversation))
+            .unwrap_or_default()
+            .max(message);
+        self.store.insert((user, conversation), last_read);
+        last_read
+    }
+
+    /// Removes the read marker of a conversation.
+    pub fn remove(&self, user: UserId, conversation: ConversationId) -> Option<MessageId> {
+        self.store.remove(&(user, conversation))
+    }
+}
+
+/// Points a search match earns per occurrence of a query term.
+const RELEVANCE_TERM_WEIGHT: i64 = 100;
+
+/// Scores a search match as [`RELEVANCE_TERM_WEIGHT`] points per occurrence of a query term
+/// in its content, minus one point per newer match. An extra occurrence therefore outweighs
+/// up to a hundred newer matches, and the score only depends on the content and the order of
+/// the matches.
+fn relevance(message: &Message, query: &[String], newer: usize) -> i64 {
+    let frequency = terms(&message.content)
+        .filter(|term| query.contains(term))
+        .count() as i64;
+    frequency * RELEVANCE_TERM_WEIGHT - newer as i64
+}
+
+/// Keeps the summary of each conversation, see [`Summary`].
+#[derive(Debug)]
+pub struct SummaryRepository<S = StableStore<ConversationId, Summary>> {
+    store: S,
+}
+
+impl Default for SummaryRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_SUMMARY)
+    }
+}
+
+impl<S: Store<ConversationId, Summary>> SummaryRepository<S> {
+    /// Builds the repository on another store, such as a [`MemoryStore`] in tests.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the summary of a conversation, if one was made.
+    pub fn get(&self, conversation: ConversationId) -> Option<Summary> {
+        self.store.get(&conversation)
+    }
+
+    /// Records `content` as the summary of every message up to `until`, replacing the previous
+    /// summary.
+    pub fn save(&self, conversation: ConversationId, content: String, until: MessageId) {
+        self.store.insert(conversation, Summary { content, until });
+    }
+
+    /// Forgets the summary of a conversation.
+    pub fn remove(&self, conversation: ConversationId) -> Option<Summary> {
+        self.store.remove(&conversation)
+    }
+}
+
+/// Keeps the settings overridden on each conversation.
+#[derive(Debug)]
+pub struct ConversationSettingsRepository<S = StableStore<ConversationId, ConversationSettings>> {
+    store: S,
+}
+
+impl Default for ConversationSettingsRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_SETTINGS)
+    }
+}
+
+impl<S: Store<ConversationId, ConversationSettings>> ConversationSettingsRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the overrides of a conversation, if any were saved.
+    pub fn get(&self, conversation: ConversationId) -> Option<ConversationSettings> {
+        self.store.get(&conversation)
+    }
+
+    /// Replaces the overrides of a conversation.
+    pub fn save(&self, conversation: ConversationId, settings: ConversationSettings) {
+        self.store.insert(conversation, settings);
+    }
+
+    /// Drops the overrides of a conversation, falling back to the deployment settings.
+    pub fn remove(&self, conversation: ConversationId) -> Option<ConversationSettings> {
+        self.store.remove(&conversation)
+    }
+}
+
+/// Remembers the last message sent with an idempotency key on each conversation, so that a
+/// retried send does not store the message twice.
+#[derive(Debug)]
+pub struct IdempotencyRepository<S = StableStore<ConversationId, IdempotencyRecord>> {
+    store: S,
+}
+
+impl Default for IdempotencyRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_IDEMPOTENCY)
+    }
+}
+
+impl<S: Store<ConversationId, IdempotencyRecord>> IdempotencyRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the message stored for `key`, if it is the last key used on the conversation.
+    pub fn get(&self, conversation: ConversationId, key: &str) -> Option<MessageId> {
+        self.store
+            .get(&conversation)
+            .filter(|r| r.key == key)
+            .map(|r| r.message)
+    }
+
+    /// Records the message stored for `key`, replacing the previous key of the conversation.
+    pub fn save(&self, conversation: ConversationId, key: String, message: MessageId) {
+        self.store
+            .insert(conversation, IdempotencyRecord { key, message });
+    }
+
+    /// Forgets the last key used on a conversation.
+    pub fn remove(&self, conversation: ConversationId) -> Option<MessageId> {
+        self.store.remove(&conversation).map(|r| r.message)
+    }
+}
+
+/// Accumulates the LLM tokens consumed by each user, prompts and completions alike.
+#[derive(Debug)]
+pub struct TokenUsageRepository<S = StableStore<UserId, u64>> {
+    store: S,
+}
+
+impl Default for TokenUsageRepository {
+    fn default() -> Self {
+        Self::with_store(&USER_TOKEN_USAGE)
+    }
+}
+
+impl<S: Store<UserId, u64>> TokenUsageRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the tokens consumed by a user so far.
+    pub fn get(&self, user: UserId) -> u64 {
+        self.store.get(&user).unwrap_or_default()
+    }
+
+    /// Adds `tokens` to the usage of a user, returning the new total.
+    pub fn add(&self, user: UserId, tokens: u64) -> u64 {
+        let total = self.get(user).saturating_add(tokens);
+        self.store.insert(user, total);
+        total
+    }
+
+    /// Starts the usage of a user over, returning the previous total.
+    pub fn reset(&self, user: UserId) -> u64 {
+        self.store.remove(&user).unwrap_or_default()
+    }
+}
+
+/// Keeps the time each user last created a conversation, whether or not the conversation
+/// still exists.
+#[derive(Debug)]
+pub struct LastCreatedRepository<S = StableStore<UserId, Timestamp>> {
+    store: S,
+}
+
+impl Default for LastCreatedRepository {
+    fn default() -> Self {
+        Self::with_store(&USER_LAST_CREATED)
+    }
+}
+
+impl<S: Store<UserId, Timestamp>> LastCreatedRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the time the user last created a conversation.
+    pub fn get(&self, user: UserId) -> Option<Timestamp> {
+        self.store.get(&user)
+    }
+
+    /// Records a creation of the user, an older time never replaces a newer one.
+    pub fn record(&self, user: UserId, created_at: Timestamp) -> Timestamp {
+        let last = self.get(user).unwrap_or_default().max(created_at);
+        self.store.insert(user, last);
+        last
+    }
+}
+
+/// Keeps the tokens of the LLM turn behind each message, see [`MessageTokens`].
+#[derive(Debug)]
+pub struct MessageTokenRepository<S = StableStore<MessageId, MessageTokens>> {
+    store: S,
+}
+
+impl Default for MessageTokenRepository {
+    fn default() -> Self {
+        Self::with_store(&CHAT_MESSAGE_TOKENS)
+    }
+}
+
+impl<S: Store<MessageId, MessageTokens>> MessageTokenRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the tokens recorded for a message, zero when none were.
+    pub fn get(&self, message: MessageId) -> MessageTokens {
+        self.store.get(&message).unwrap_or_default()
+    }
+
+    /// Records the tokens of the turn which produced a message.
+    pub fn save(&self, message: MessageId, tokens: MessageTokens) {
+        self.store.insert(message, tokens);
+    }
+}
+
+/// Keeps the draft of each conversation, see [`Draft`].
+#[derive(Debug)]
+pub struct DraftRepository<S = StableStore<ConversationId, Draft>> {
+    store: S,
+}
+
+impl Default for DraftRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_DRAFT)
+    }
+}
+
+impl<S: Store<ConversationId, Draft>> DraftRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves the draft of a conversation, if one was saved.
+    pub fn get(&self, conversation: ConversationId) -> Option<Draft> {
+        self.store.get(&conversation)
+    }
+
+    /// Replaces the draft of a conversation, stamped with the current time.
+    pub fn save(&self, conversation: ConversationId, content: String) -> Draft {
+        let draft = Draft {
+            content,
+            updated_at: timestamp(),
+        };
+        self.store.insert(conversation, draft.clone());
+        draft
+    }
+
+    /// Drops the draft of a conversation.
+    pub fn remove(&self, conversation: ConversationId) -> Option<Draft> {
+        self.store.remove(&conversation)
+    }
+}
+
+/// Keeps who named each conversation last, see [`TitleSource`]. Conversations still holding
+/// the name given on creation have no entry.
+#[derive(Debug)]
+pub struct TitleRepository<S = StableStore<ConversationId, TitleSource>> {
+    store: S,
+}
+
+impl Default for TitleRepository {
+    fn default() -> Self {
+        Self::with_store(&CONVERSATION_TITLE)
+    }
+}
+
+impl<S: Store<ConversationId, TitleSource>> TitleRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Retrieves who named a conversation last, `None` when it was never renamed.
+    pub fn get(&self, conversation: ConversationId) -> Option<TitleSource> {
+        self.store.get(&conversation)
+    }
+
+    /// Records who named a conversation last.
+    pub fn save(&self, conversation: ConversationId, source: TitleSource) {
+        self.store.insert(conversation, source);
+    }
+
+    /// Forgets who named a conversation.
+    pub fn remove(&self, conversation: ConversationId) -> Option<TitleSource> {
+        self.store.remove(&conversation)
+    }
+}
+
+/// Keeps the tags of each conversation, along with an index of the conversations of a user by
+/// tag. Both maps are written together, a tag is in either both or none.
+#[derive(Debug)]
+pub struct ConversationTagRepository<S: Storage = StableStorage> {
+    tags: S::Map<ConversationTag, ()>,
+    index: S::Map<TaggedConversation, ()>,
+    conversations: S::Map<ConversationId, Conversation>,
+}
+
+impl Default for ConversationTagRepository {
+    fn default() -> Self {
+        Self::with_storage(&StableStorage)
+    }
+}
+
+impl<S: Storage> ConversationTagRepository<S> {
+    /// Builds the repository on the maps of `storage`.
+    pub fn with_storage(storage: &S) -> Self {
+        Self {
+            tags: storage.conversation_tags(),
+            index: storage.conversation_tag_index(),
+            conversations: storage.conversation_map(),
+        }
+    }
+
+    /// Retrieves the tags of a conversation, in lexicographic order.
+    pub fn tags(&self, conversation: ConversationId) -> Vec<String> {
+        let start = ConversationTag {
+            conversation,
+            tag: String::new(),
+        };
+        self.tags.walk(start.., |entries| {
+            entries
+                .take_while(|(k, _)| k.conversation == conversation)
+                .map(|(k, _)| k.tag)
+                .collect_vec()
+        })
+    }
+
+    /// Retrieves the conversations of a user carrying the tag, in id order.
+    pub fn tagged(&self, user: UserId, tag: &str) -> Vec<ConversationId> {
+        let start = TaggedConversation {
+            user,
+            tag: tag.to_string(),
+            conversation: 0,
+        };
+        self.index.walk(start.., |entries| {
+            entries
+                .take_while(|(k, _)| k.user == user && k.tag == tag)
+                .map(|(k, _)| k.conversation)
+                .collect_vec()
+        })
+    }
+
+    /// Retrieves the distinct tags of a user along with the count of conversations carrying
+    /// each, in lexicographic order. Conversations in the trash are left out.
+    pub fn list_tags(&self, user: UserId) -> Vec<(String, u64)> {
+        let start = TaggedConversation {
+            user,
+            tag: String::new(),
+            conversation: 0,
+        };
+        self.index.walk(start.., |entries| {
+            entries
+                .take_while(|(k, _)| k.user == user)
+                .filter(|(k, _)| {
+                    self.conversations
+                        .get(&k.conversation)
+                        .is_some_and(|c| c.deleted_at.is_none())
+                })
+                .map(|(k, _)| k.tag)
+                .dedup_with_count()
+                .map(|(count, tag)| (tag, count as u64))
+                .collect_vec()
+        })
+    }
+
+    /// Tags a conversation of `user`, `false` when it already carried the tag.
+    pub fn add(&self, user: UserId, conversation: ConversationId, tag: &str) -> bool {
+        let added = self
+            .tags
+            .insert(
+                ConversationTag {
+                    conversation,
+                    tag: tag.to_string(),
+                },
+                (),
+            )
+            .is_none();
+        self.index.insert(
+            TaggedConversation {
+                user,
+                tag: tag.to_string(),
+                conversation,
+            },
+            (),
+        );
+        added
+    }
+
+    /// Removes a tag from a conversation of `user`, `false` when it did not carry the tag.
+    pub fn remove(&self, user: UserId, conversation: ConversationId, tag: &str) -> bool {
+        let removed = self
+            .tags
+            .remove(&ConversationTag {
+                conversation,
+                tag: tag.to_string(),
+            })
+            .is_some();
+        self.index.remove(&TaggedConversation {
+            user,
+            tag: tag.to_string(),
+            conversation,
+        });
+        removed
+    }
+
+    /// Removes every tag of a conversation of `user`.
+    pub fn remove_all(&self, user: UserId, conversation: ConversationId) {
+        for tag in self.tags(conversation) {
+            self.remove(user, conversation, &tag);
+        }
+    }
+}
+
+/// Flags the conversations having a turn in flight, so that turns do not interleave.
+#[derive(Debug)]
+pub struct InFlightRepository<S = MemoryStore<ConversationId, ()>> {
+    store: S,
+}
+
+impl Default for InFlightRepository {
+    fn default() -> Self {
+        Self::with_store(CONVERSATION_IN_FLIGHT.with(MemoryStore::clone))
+    }
+}
+
+impl<S: Store<ConversationId, ()>> InFlightRepository<S> {
+    /// Builds the repository on another store.
+    pub fn with_store(store: S) -> Self {
+        Self { store }
+    }
+
+    /// Flags a turn as started on the conversation, `false` when one is already in flight.
+    pub fn acquire(&self, conversation: ConversationId) -> bool {
+        self.store.insert(conversation, ()).is_none()
+    }
+
+    /// Clears the flag of the conversation once its turn finished.
+    pub fn release(&self, conversation: ConversationId) {
+        self.store.remove(&conversation);
+    }
+
+    /// Tells whether a turn is in flight on the conversation.
+    pub fn is_in_flight(&self, conversation: ConversationId) -> bool {
+        self.store.contains_key(&conversation)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KnowledgeRepository;
+
+impl SerialIdRepository for KnowledgeRepository {
+    type Generator = StableIdGenerator;
+
+    fn generator(&self) -> StableIdGenerator {
+        &NEXT_KNOWLEDGE_ID
+    }
+}
+
+impl Repository<QaId, QaEntry> for KnowledgeRepository {
+    fn get(&self, id: &QaId) -> Option<QaEntry> {
+        KNOWLEDGE.with_borrow(|m| m.get(id))
+    }
+
+    fn insert(&self, mut entry: QaEntry) -> RepositoryResult<QaEntry> {
+        entry.id = self.next_id();
+        KNOWLEDGE.with_borrow_mut(|m| m.insert(entry.id, entry.clone()));
+        Ok(entry)
+    }
+
+    fn update(&self, entry: QaEntry) -> RepositoryResult<QaEntry> {
+        if self.get(&entry.id).is_none() {
+            return Err(RepositoryError::NotFound);
+        }
+        KNOWLEDGE.with_borrow_mut(|m| m.insert(entry.id, entry.clone()));
+        Ok(entry)
+    }
+
+    fn delete(&self, id: &QaId) -> RepositoryResult<QaId> {
+        KNOWLEDGE
+            .with_borrow_mut(|m| m.remove(id))
+            .map(|_| *id)
+            .ok_or(RepositoryError::NotFound)
+    }
+}
+
+impl KnowledgeRepository {
+    /// Finds the entries sharing the most terms with the query, from the question and tags.
+    /// Entries without any common term are left out, ties are broken by the oldest entry.
+    pub fn find_relevant(&self, query: &str, limit: usize) -> Vec<QaEntry> {
+        let query = terms(query).collect::<HashSet<_>>();
+        KNOWLEDGE.with_borrow(|m| {
+            m.iter()
+                .map(|(_, entry)| {
+                    let score = terms(&entry.question)
+                        .chain(entry.tags.iter().flat_map(|t| terms(t)))
+                        .collect::<HashSet<_>>()
+                        .intersection(&query)
+                        .count();
+                    (score, entry)
+                })
+                .filter(|(score, _)| *score > 0)
+                .sorted_by_key(|(score, entry)| (Reverse(*score), entry.id))
+                .take(limit)
+                .map(|(_, entry)| entry)
+                .collect_vec()
+        })
+    }
+}
+
+/// Version of the [`Archive`] layout written by [`export_all`].
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Full backup of the users, conversations and messages.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Archive {
+    pub version: u32,
+    pub users: Vec<User>,
+    pub conversations: Vec<Conversation>,
+    pub messages: Vec<Message>,
+}
+
+/// Amount of entities restored by [`import_all`].
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ArchiveSummary {
+    pub users: u64,
+    pub conversations: u64,
+    pub messages: u64,
+}
+
+/// Outcome of [`quarantine_orphans`], which carries on past a failing message.
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct OrphanReport {
+    /// Orphans moved into the quarantine conversation, or deleted when `delete_orphans` is set.
+    pub handled: Vec<MessageId>,
+    /// Orphans left in place, with the reason.
+    pub failed: Vec<(MessageId, String)>,
+}
+
+/// Moves the messages whose conversation no longer exists into the quarantine conversation,
+/// created on demand, or deletes them when `delete_orphans` is set. Their indexes follow, and
+/// a moved message no longer replies to anything since the message it answered stays behind.
+pub fn quarantine_orphans<S: Storage>(storage: &S) -> RepositoryResult<OrphanReport> {
+    let messages = MessageRepository::with_storage(storage);
+    let conversations = ConversationRepository::with_storage(storage);
+    let conversation_map = storage.conversation_map();
+    let orphans = messages
+        .slice(0, 0)
+        .into_iter()
+        .map(|(id, msg)| (id, msg.conversation))
+        .filter(|(_, conversation)| !conversation_map.contains_key(conversation))
+        .map(|(id, _)| id)
+        .collect_vec();
+    let mut report = OrphanReport::default();
+    if orphans.is_empty() {
+        return Ok(report);
+    }
+    let quarantine = if settings::get().delete_orphans {
+        None
+    } else {
+        let found = conversations.find_by_exact_name(NEW_ENTITY_ID, QUARANTINE_CONVERSATION_NAME);
+        Some(match found {
+            Some(quarantine) => quarantine.id,
+            None => {
+                conversations
+                    .create(QUARANTINE_CONVERSATION_NAME.to_string(), NEW_ENTITY_ID)?
+                    .id
+            }
+        })
+    };
+    for id in orphans {
+        let handled = match quarantine {
+            Some(quarantine) => messages.move_to(id, quarantine).map(|m| m.id),
+            None => messages.delete(&id),
+        };
+        match handled {
+            Ok(id) => report.handled.push(id),
+            Err(e) => report.failed.push((id, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// Serializes every user, conversation and message into a CBOR [`Archive`].
+pub fn export_all() -> Vec<u8> {
+    let archive = Archive {
+        version: ARCHIVE_VERSION,
+        users: USER.with_borrow(|m| m.iter().map(|(_, v)| v).collect()),
+        conversations: CONVERSATION.with_borrow(|m| m.iter().map(|(_, v)| v).collect()),
+        messages: CHAT_MESSAGE.with_borrow(|m| m.iter().map(|(_, v)| v).collect()),
+    };
+    let mut encoded = Vec::new();
+    ciborium::into_writer(&archive, &mut encoded).unwrap();
+    encoded
+}
+
+/// Restores an archive made by [`export_all`]. Every map wiped by [`clear_all`] must be empty,
+/// so that nothing left over points at the restored ids.
+///
+/// Stored records keep their content and timestamps, but their ids are generated again by the
+/// serials; the references between them are remapped and the indexes rebuilt accordingly.
+pub fn import_all(bytes: &[u8]) -> RepositoryResult<ArchiveSummary> {
+    let archive: Archive =
+        ciborium::from_reader(bytes).map_err(|e| RepositoryError::InvalidData {
+            reason: e.to_string(),
+        })?;
+    if archive.version > ARCHIVE_VERSION {
+        return Err(RepositoryError::InvalidData {
+            reason: format!("unsupported archive version {}", archive.version),
+        });
+    }
+    if !is_storage_empty() {
+        return Err(RepositoryError::Conflict);
+    }
+
+    let user_repo = UserRepository::default();
+    let user_ids: HashMap<UserId, UserId> = archive
+        .users
+        .into_iter()
+        .map(|mut user| {
+            let old_id = user.id;
+            user.id = user_repo.next_id();
+            USER.with_borrow_mut(|m| m.insert(user.id, user.clone()));
+            user_repo.add_indexes(&user);
+            (old_id, user.id)
+        })
+        .collect();
+
+    let conv_repo = ConversationRepository::default();
+    let conv_ids: HashMap<ConversationId, ConversationId> = archive
+        .conversations
+        .into_iter()
+        .map(|mut conv| {
+            let old_id = conv.id;
+            conv.id = conv_repo.next_id();
+            conv.user = user_ids.get(&conv.user).copied().unwrap_or(conv.user);
+            CONVERSATION_CACHE.with_borrow_mut(|c| c.invalidate(conv.id));
+            CONVERSATION.with_borrow_mut(|m| m.insert(conv.id, conv.clone()));
+            conv_repo.add_indexes(&conv);
+            (old_id, conv.id)
+        })
+        .collect();
+
+    // oldest first, so a replied message is always remapped before its replies
+    let msg_repo = MessageRepository::default();
+    let mut msg_ids: HashMap<MessageId, MessageId> = HashMap::new();
+    archive
+        .messages
+        .into_iter()
+        .sorted_by_key(|m| m.id)
+        .for_each(|mut msg| {
+            let old_id = msg.id;
+            msg.id = msg_repo.next_id();
+            msg.conversation = conv_ids
+                .get(&msg.conversation)
+                .copied()
+                .unwrap_or(msg.conversation);
+            msg.reply_to = msg.reply_to.and_then(|id| msg_ids.get(&id).copied());
+            CHAT_MESSAGE.with_borrow_mut(|m| m.insert(Reverse(msg.id), msg.clone()));
+            msg_repo.add_indexes(&msg);
+            msg_ids.insert(old_id, msg.id);
+        });
+
+    Ok(ArchiveSummary {
+        users: user_ids.len() as u64,
+        conversations: conv_ids.len() as u64,
+        messages: msg_ids.len() as u64,
+    })
+}
+
+/// Empties every map and index of the storage and restarts every serial at 1.
+pub fn clear_all() {
+    [
+        &NEXT_CHAT_MESSAGE_ID,
+        &NEXT_CONVERSATION_ID,
+        &NEXT_USER_ID,
+        &NEXT_KNOWLEDGE_ID,
+    ]
+    .iter()
+    .for_each(|serial| {
+        serial.with_borrow_mut(|s| s.set(1).expect("failed to reset serial"));
+    });
+    CHAT_MESSAGE.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_REPLY_INDEX.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_TERM_INDEX.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_TIMESTAMP_INDEX.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_CACHE.with_borrow_mut(|c| c.clear());
+    CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_CREATED_INDEX.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_STALE_INDEX.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_TRASH_INDEX.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_READ.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_SUMMARY.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_SETTINGS.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_IDEMPOTENCY.with_borrow_mut(|m| m.clear_new());
+    USER.with_borrow_mut(|m| m.clear_new());
+    USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.clear_new());
+    USER_TOKEN_USAGE.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_TAG.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_TAG_INDEX.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_TOKENS.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_DRAFT.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_TITLE.with_borrow_mut(|m| m.clear_new());
+    CHAT_MESSAGE_TOMBSTONE.with_borrow_mut(|m| m.clear_new());
+    CONVERSATION_TOMBSTONE.with_borrow_mut(|m| m.clear_new());
+    USER_LAST_CREATED.with_borrow_mut(|m| m.clear_new());
+    USER_MESSAGE_COUNT.with_borrow_mut(|m| m.clear_new());
+    USER_PINNED_COUNT.with_borrow_mut(|m| m.clear_new());
+    KNOWLEDGE.with_borrow_mut(|m| m.clear_new());
+}
+
+/// Tells whether every map wiped by [`clear_all`] is empty.
+fn is_storage_empty() -> bool {
+    CHAT_MESSAGE.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_REPLY_INDEX.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_TERM_INDEX.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_TIMESTAMP_INDEX.with_borrow(|m| m.is_empty())
+        && CONVERSATION.with_borrow(|m| m.is_empty())
+        && CONVERSATION_USER_INDEX.with_borrow(|m| m.is_empty())
+        && CONVERSATION_CREATED_INDEX.with_borrow(|m| m.is_empty())
+        && CONVERSATION_STALE_INDEX.with_borrow(|m| m.is_empty())
+        && CONVERSATION_TRASH_INDEX.with_borrow(|m| m.is_empty())
+        && CONVERSATION_READ.with_borrow(|m| m.is_empty())
+        && CONVERSATION_SUMMARY.with_borrow(|m| m.is_empty())
+        && CONVERSATION_SETTINGS.with_borrow(|m| m.is_empty())
+        && CONVERSATION_IDEMPOTENCY.with_borrow(|m| m.is_empty())
+        && USER.with_borrow(|m| m.is_empty())
+        && USER_PRINCIPAL_INDEX.with_borrow(|m| m.is_empty())
+        && USER_TOKEN_USAGE.with_borrow(|m| m.is_empty())
+        && CONVERSATION_TAG.with_borrow(|m| m.is_empty())
+        && CONVERSATION_TAG_INDEX.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_TOKENS.with_borrow(|m| m.is_empty())
+        && CONVERSATION_DRAFT.with_borrow(|m| m.is_empty())
+        && CONVERSATION_TITLE.with_borrow(|m| m.is_empty())
+        && CHAT_MESSAGE_TOMBSTONE.with_borrow(|m| m.is_empty())
+        && CONVERSATION_TOMBSTONE.with_borrow(|m| m.is_empty())
+        && USER_LAST_CREATED.with_borrow(|m| m.is_empty())
+        && USER_MESSAGE_COUNT.with_borrow(|m| m.is_empty())
+        && USER_PINNED_COUNT.with_borrow(|m| m.is_empty())
+        && KNOWLEDGE.with_borrow(|m| m.is_empty())
+}
+
+lazy_static! {
+    pub static ref MESSAGE_REPOSITORY: Arc<MessageRepository> =
+        Arc::new(MessageRepository::default());
+    pub static ref CONVERSATION_REPOSITORY: Arc<ConversationRepository> =
+        Arc::new(ConversationRepository::default());
+    pub static ref USER_REPOSITORY: Arc<UserRepository> = Arc::new(UserRepository::default());
+    pub static ref KNOWLEDGE_REPOSITORY: Arc<KnowledgeRepository> =
+        Arc::new(KnowledgeRepository::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::utils::mock_ic0;
+
+    use super::*;
+
+    fn reset_msg_data() {
+        CHAT_MESSAGE.with_borrow_mut(|m| m.clear_new());
+        CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow_mut(|m| m.clear_new());
+        CHAT_MESSAGE_REPLY_INDEX.with_borrow_mut(|m| m.clear_new());
+        CHAT_MESSAGE_TERM_INDEX.with_borrow_mut(|m| m.clear_new());
+        CHAT_MESSAGE_TIMESTAMP_INDEX.with_borrow_mut(|m| m.clear_new());
+        CHAT_MESSAGE_TOKENS.with_borrow_mut(|m| m.clear_new());
+        NEXT_CHAT_MESSAGE_ID.with_borrow_mut(|v| v.set(1).unwrap());
+    }
+
+    fn reset_conv_data() {
+        CONVERSATION.with_borrow_mut(|m| m.clear_new());
+        CONVERSATION_CACHE.with_borrow_mut(|c| c.clear());
+        CONVERSATION_USER_INDEX.with_borrow_mut(|m| m.clear_new());
+        CONVERSATION_CREATED_INDEX.with_borrow_mut(|m| m.clear_new());
+        CONVERSATION_TRASH_INDEX.with_borrow_mut(|m| m.clear_new());
+        NEXT_CONVERSATION_ID.with_borrow_mut(|v| v.set(1).unwrap());
+    }
+
+    fn reset_user_data() {
+        USER.with_borrow_mut(|m| m.clear_new());
+        USER_PRINCIPAL_INDEX.with_borrow_mut(|m| m.clear_new());
+        NEXT_USER_ID.with_borrow_mut(|m| m.set(1).unwrap());
+    }
+
+    #[test]
+    fn map_message_should_valid() {
+        let m = Message {
+            id: 1,
+            conversation: 1,
+            content: "hi text!".to_string(),
+            timestamp: 1,
+            role: Roles::User,
+            reply_to: None,
+        };
+        let mapped = m.to_ic_message();
+        assert_eq!(m.content, mapped.content);
+    }
+
+    #[test]
+    fn map_role_should_valid() {
+        let r = Roles::Assistant;
+        let mapped = r.to_ic_role();
+        assert!(matches!(mapped, Role::Assistant));
+        let r = Roles::System;
+        let mapped = r.to_ic_role();
+        assert!(matches!(mapped, Role::System));
+    }
+
+    #[test]
+    fn parse_roles_from_str_should_works() {
+        let r = "user".parse::<Roles>().unwrap();
+        assert!(matches!(r, Roles::User));
+        let r: Roles = "assistant".parse().unwrap();
+        assert!(matches!(r, Roles::Assistant));
+        let s = String::from("system");
+        let r: Roles = s.parse().unwrap();
+        assert!(matches!(r, Roles::System));
+    }
+
+    #[test]
+    #[should_panic]
+    fn parse_roles_from_unknown_str_should_failed() {
+        "jedi".parse::<Roles>().unwrap();
+    }
+
+    #[test]
+    fn storable_encoding_decoding_should_valid() {
+        let message = Message {
+            id: 1,
+            conversation: 1,
+            content: "Hello, world!".to_string(),
+            timestamp: 1234567890,
+            role: Roles::User,
+            reply_to: None,
+        };
+        let encoded_message = message.to_bytes();
+        let decoded_message = Message::from_bytes(encoded_message);
+        assert_eq!(message, decoded_message);
+
+        let conversation = Conversation {
+            id: 1,
+            user: 1,
+            updated_at: 1234567890,
+            created_at: 0,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
+            name: "Test Conversation".to_string(),
+        };
+        let encoded_conversation = conversation.to_bytes();
+        let decoded_conversation = Conversation::from_bytes(encoded_conversation);
+        assert_eq!(conversation, decoded_conversation);
+
+        let user = User {
+            id: 1,
+            fullname: "test_user".to_string(),
+            identity: Principal::anonymous(),
+            resume: "engineer".to_string(),
+        };
+        let encoded_user = user.to_bytes();
+        let decoded_user = User::from_bytes(encoded_user);
+        assert_eq!(user, decoded_user);
+    }
+
+    fn generated_id_should_consistent<S: Storage>(storage: S) {
+        let msg_repo = MessageRepository::with_storage(&storage);
+        let con_repo = ConversationRepository::with_storage(&storage);
+
+        assert_eq!(msg_repo.peek_next_id(), 1);
+        assert_eq!(con_repo.peek_next_id(), 1);
+
+        assert_eq!(msg_repo.next_id(), 1);
+        assert_eq!(msg_repo.peek_next_id(), 2);
+        assert_eq!(con_repo.next_id(), 1);
+        assert_eq!(con_repo.peek_next_id(), 2);
+        assert_eq!(con_repo.next_id(), 2);
+        assert_eq!(con_repo.peek_next_id(), 3);
+    }
+
+    fn get_and_insert_message_should_work<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        repo.insert(Message {
+            id: 123,
+            conversation: 1,
+            content: "Hi World!".to_string(),
+            timestamp: 123,
+            role: Roles::User,
+            reply_to: None,
+        })
+        .unwrap();
+        assert!(repo.get(&123).is_none());
+        assert!(repo.get(&1).is_some());
+        assert_eq!("Hi World!".to_string(), repo.get(&1).unwrap().content)
+    }
+
+    fn insert_message_should_stop_at_message_cap<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let message = Message {
+            id: 0,
+            conversation: 1,
+            content: "Hi World!".to_string(),
+            timestamp: 0,
+            role: Roles::User,
+            reply_to: None,
+        };
+        settings::update(|s| s.max_messages = 2);
+        assert!(repo.insert(message.clone()).is_ok());
+        assert!(repo.insert(message.clone()).is_ok());
+        assert_eq!(
+            Err(RepositoryError::QuotaExceeded { limit: 2 }),
+            repo.insert(message.clone())
+        );
+        assert_eq!(2, repo.count());
+
+        settings::update(|s| s.max_messages = 3);
+        assert!(repo.insert(message).is_ok());
+        assert_eq!(3, repo.count());
+    }
+
+    fn count_since_should_count_newer_messages_only<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let ids = (0..4)
+            .map(|i| {
+                repo.insert(Message {
+                    id: 0,
+                    conversation: 1 + i % 2,
+                    content: format!("message {}", i),
+                    timestamp: 0,
+                    role: Roles::User,
+                    reply_to: None,
+                })
+                .unwrap()
+                .id
+            })
+            .collect_vec();
+
+        assert_eq!(2, repo.count_since(1, 0));
+        assert_eq!(1, repo.count_since(1, ids[0]));
+        assert_eq!(1, repo.count_since(1, ids[1]));
+        assert_eq!(0, repo.count_since(1, ids[2]));
+        assert_eq!(0, repo.count_since(1, MessageId::MAX));
+        assert_eq!(1, repo.count_since(2, ids[2]));
+        assert_eq!(0, repo.count_since(3, 0));
+    }
+
+    fn count_by_user_should_follow_inserts_and_deletes<S: Storage>(storage: S) {
+        let conversations = ConversationRepository::with_storage(&storage);
+        let first = conversations.create("first".to_string(), 1).unwrap();
+        let second = conversations.create("second".to_string(), 1).unwrap();
+        let other = conversations.create("other".to_string(), 2).unwrap();
+        let repo = MessageRepository::with_storage(&storage);
+        let ids = [first.id, first.id, second.id, other.id]
+            .into_iter()
+            .map(|conversation| {
+                repo.insert(Message {
+                    id: 0,
+                    conversation,
+                    content: "hello".to_string(),
+                    timestamp: 0,
+                    role: Roles::User,
+                    reply_to: None,
+                })
+                .unwrap()
+                .id
+            })
+            .collect_vec();
+        assert_eq!(3, repo.count_by_user(1));
+        assert_eq!(1, repo.count_by_user(2));
+
+        repo.delete(&ids[0]).unwrap();
+        assert_eq!(2, repo.count_by_user(1));
+        repo.reindex();
+        assert_eq!(2, repo.count_by_user(1));
+        assert_eq!(1, repo.count_by_user(2));
+
+        repo.delete_by_conversation(&first.id, 0).unwrap();
+        repo.delete_by_conversation(&second.id, 0).unwrap();
+        assert_eq!(0, repo.count_by_user(1));
+        assert_eq!(1, repo.count_by_user(2));
+        assert_eq!(0, repo.count_by_user(3));
+    }
+
+    fn store_suite<S: Store<UserId, u64>>(store: S) {
+        assert!(store.is_empty());
+        assert_eq!(None, store.insert(3, 30));
+        assert_eq!(None, store.insert(1, 10));
+        assert_eq!(Some(30), store.insert(3, 32));
+        store.insert(2, 20);
+        assert_eq!(Some(32), store.get(&3));
+        assert_eq!(3, store.len());
+        assert_eq!(vec![(1, 10), (2, 20)], store.range(0, 2));
+        assert!(store.range(3, 1).is_empty());
+        assert_eq!(Some(20), store.remove(&2));
+        assert_eq!(None, store.remove(&2));
+        assert_eq!(vec![(1, 10), (3, 32)], store.range(0, 9));
+        assert!(store.contains_key(&3));
+        assert!(!store.contains_key(&2));
+        assert_eq!(
+            vec![3, 1],
+            store.walk(.., |entries| entries.rev().map(|(k, _)| k).collect_vec())
+        );
+        store.clear();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn stores_should_behave_alike() {
+        store_suite(&USER_TOKEN_USAGE);
+        store_suite(MemoryStore::default());
+    }
+
+    fn summary_suite<S: Store<ConversationId, Summary>>(repo: SummaryRepository<S>) {
+        let summary = |content: &str, until| Summary {
+            content: content.to_string(),
+            until,
+        };
+        assert_eq!(None, repo.get(1));
+        repo.save(1, "first".to_string(), 4);
+        repo.save(1, "second".to_string(), 8);
+        repo.save(2, "other".to_string(), 2);
+        assert_eq!(Some(summary("second", 8)), repo.get(1));
+        assert_eq!(Some(summary("second", 8)), repo.remove(1));
+        assert_eq!(None, repo.get(1));
+        assert_eq!(Some(summary("other", 2)), repo.get(2));
+    }
+
+    fn token_usage_suite<S: Store<UserId, u64>>(repo: TokenUsageRepository<S>) {
+        assert_eq!(0, repo.get(1));
+        assert_eq!(30, repo.add(1, 30));
+        assert_eq!(42, repo.add(1, 12));
+        assert_eq!(u64::MAX, repo.add(2, u64::MAX));
+        assert_eq!(u64::MAX, repo.add(2, 1));
+        assert_eq!(42, repo.reset(1));
+        assert_eq!(0, repo.get(1));
+    }
+
+    fn draft_suite<S: Store<ConversationId, Draft>>(repo: DraftRepository<S>) {
+        assert_eq!(None, repo.get(1));
+        let first = repo.save(1, "Dear".to_string());
+        let second = repo.save(1, "Dear hiring manager".to_string());
+        assert!(second.updated_at > first.updated_at);
+        assert_eq!(Some(second.clone()), repo.get(1));
+        assert_eq!(Some(second), repo.remove(1));
+        assert_eq!(None, repo.get(1));
+    }
+
+    fn last_created_suite<S: Store<UserId, Timestamp>>(repo: LastCreatedRepository<S>) {
+        assert_eq!(None, repo.get(1));
+        assert_eq!(20, repo.record(1, 20));
+        assert_eq!(20, repo.record(1, 10));
+        assert_eq!(Some(20), repo.get(1));
+        assert_eq!(30, repo.record(1, 30));
+        assert_eq!(None, repo.get(2));
+    }
+
+    fn title_suite<S: Store<ConversationId, TitleSource>>(repo: TitleRepository<S>) {
+        assert_eq!(None, repo.get(1));
+        repo.save(1, TitleSource::Generated);
+        repo.save(1, TitleSource::Manual);
+        assert_eq!(Some(TitleSource::Manual), repo.get(1));
+        assert_eq!(Some(TitleSource::Manual), repo.remove(1));
+        assert_eq!(None, repo.get(1));
+    }
+
+    fn read_marker_suite<S: Store<(UserId, ConversationId), MessageId>>(
+        repo: ReadMarkerRepository<S>,
+    ) {
+        assert_eq!(None, repo.get(1, 1));
+        assert_eq!(5, repo.mark(1, 1, 5));
+        assert_eq!(5, repo.mark(1, 1, 3));
+        assert_eq!(Some(5), repo.get(1, 1));
+        assert_eq!(None, repo.get(2, 1));
+        assert_eq!(Some(5), repo.remove(1, 1));
+        assert_eq!(None, repo.get(1, 1));
+    }
+
+    fn idempotency_suite<S: Store<ConversationId, IdempotencyRecord>>(
+        repo: IdempotencyRepository<S>,
+    ) {
+        assert_eq!(None, repo.get(1, "first"));
+        repo.save(1, "first".to_string(), 10);
+        assert_eq!(Some(10), repo.get(1, "first"));
+        repo.save(1, "second".to_string(), 12);
+        assert_eq!(None, repo.get(1, "first"));
+        assert_eq!(Some(12), repo.get(1, "second"));
+        assert_eq!(Some(12), repo.remove(1));
+        assert_eq!(None, repo.get(1, "second"));
+    }
+
+    fn in_flight_suite<S: Store<ConversationId, ()>>(repo: InFlightRepository<S>) {
+        assert!(!repo.is_in_flight(1));
+        assert!(repo.acquire(1));
+        assert!(!repo.acquire(1));
+        assert!(repo.is_in_flight(1));
+        assert!(!repo.is_in_flight(2));
+        repo.release(1);
+        assert!(!repo.is_in_flight(1));
+        assert!(repo.acquire(1));
+    }
+
+    #[test]
+    fn repositories_should_pass_on_both_stores() {
+        read_marker_suite(ReadMarkerRepository::default());
+        read_marker_suite(ReadMarkerRepository::with_store(MemoryStore::default()));
+        idempotency_suite(IdempotencyRepository::default());
+        idempotency_suite(IdempotencyRepository::with_store(MemoryStore::default()));
+        in_flight_suite(InFlightRepository::default());
+        summary_suite(SummaryRepository::default());
+        summary_suite(SummaryRepository::with_store(MemoryStore::default()));
+        token_usage_suite(TokenUsageRepository::default());
+        token_usage_suite(TokenUsageRepository::with_store(MemoryStore::default()));
+        draft_suite(DraftRepository::default());
+        draft_suite(DraftRepository::with_store(MemoryStore::default()));
+        title_suite(TitleRepository::default());
+        title_suite(TitleRepository::with_store(MemoryStore::default()));
+        last_created_suite(LastCreatedRepository::default());
+        last_created_suite(LastCreatedRepository::with_store(MemoryStore::default()));
+    }
+
+    /// Runs each test generic over [`Storage`] once on the stable maps and once on the heap.
+    macro_rules! on_both_storages {
+        ($($(#[$attr:meta])* $name:ident,)*) => {
+            mod stable {
+                use super::*;
+
+                $(
+                    #[test]
+                    $(#[$attr])*
+                    fn $name() {
+                        clear_all();
+                        super::$name(StableStorage);
+                    }
+                )*
+            }
+
+            mod heap {
+                use super::*;
+
+                $(
+                    #[test]
+                    $(#[$attr])*
+                    fn $name() {
+                        super::$name(HeapStorage::default());
+                    }
+                )*
+            }
+        };
+    }
+
+    on_both_storages! {
+        generated_id_should_consistent,
+        get_and_insert_message_should_work,
+        insert_message_should_stop_at_message_cap,
+        count_since_should_count_newer_messages_only,
+        count_by_user_should_follow_inserts_and_deletes,
+        quarantine_orphans_should_move_messages_of_missing_conversations,
+        store_range_should_log_and_skip_inverted_bounds,
+        index_find_should_be_empty_past_the_oldest_entry,
+        first_message_should_be_the_oldest_of_the_conversation,
+        id_gaps_should_report_deleted_ids,
+        by_id_range_should_list_ascending_ids_across_conversations,
+        message_page_should_skip_previous_pages,
+        builders_should_leave_ids_to_repositories,
+        get_or_err_should_report_missing_entity,
+        #[should_panic] update_message_should_failed,
+        delete_message_should_work,
+        #[should_panic] delete_non_exist_message_should_failed,
+        delete_messge_by_conversation_should_work,
+        delete_message_by_conversation_should_report_failures,
+        delete_message_by_conversation_should_resume_in_batches,
+        batched_reindex_should_match_full_reindex,
+        reply_and_list_replies_should_work,
+        reply_across_conversation_should_failed,
+        message_cursor_paged_list_should_return_correct_list,
+        message_paged_list_should_not_skip_after_deletions,
+        message_paged_list_cursor_should_pass_missing_messages,
+        messages_around_should_clamp_at_conversation_boundaries,
+        search_messages_should_match_every_term_of_the_query,
+        search_page_should_scroll_without_duplicates,
+        search_messages_by_relevance_should_favor_term_frequency,
+        recent_messages_should_page_across_conversations,
+        recent_messages_should_tie_break_equal_timestamps_by_id,
+        get_and_upsert_conversation_should_work,
+        upsert_new_conversation_should_always_create,
+        archive_inactive_should_only_archive_eligible_conversations,
+        stale_index_should_page_oldest_first,
+        update_conversation_should_reject_owner_change,
+        insert_conversation_should_notify_event_sink,
+        most_recent_conversation_should_skip_archived_ones,
+        trashed_conversation_should_only_be_in_trash_index,
+        delete_conversation_should_work,
+        delete_should_leave_tombstones_for_the_owner,
+        tombstones_should_be_pruned_before_the_horizon,
+        #[should_panic] delete_non_exist_conversation_should_failed,
+        conversation_cursor_paged_list_should_return_correct_list,
+        conversation_paged_list_should_follow_direction,
+        conversation_name_should_pass_filter_when_enabled,
+        set_pinned_should_stop_at_pin_limit,
+        trashed_pin_should_keep_its_slot,
+        find_by_exact_name_should_return_newest_match,
+        conversation_paged_list_should_skip_empty_when_asked,
+        conversation_paged_list_by_created_should_keep_creation_order,
+        find_values_should_match_find_and_get,
+        get_and_insert_user_should_work,
+        update_user_should_work,
+        delete_user_should_work,
+        #[should_panic] delete_non_exist_user_should_failed,
+        get_user_by_identity_should_work,
+        upsert_user_by_principal_should_insert_then_update,
+        user_resume_should_be_redacted_only_when_enabled,
+        user_resume_should_be_rejected_over_byte_cap,
+        user_resume_over_a_lowered_cap_should_stay_editable_without_growing,
+        conversation_tags_should_stay_in_sync_with_their_index,
+    }
+
+    fn store_range_should_log_and_skip_inverted_bounds<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        repo.create("first".to_string(), 1).unwrap();
+        repo.create("second".to_string(), 1).unwrap();
+
+        let index = storage.conversation_user_index();
+        let inverted = index.range(
+            (1, Reverse(0), 0),
+            (1, Reverse(Timestamp::MAX), ConversationId::MAX),
+        );
+        assert!(inverted.is_empty());
+        assert!(mock_ic0::logs()
+            .iter()
+            .any(|l| l.starts_with("inverted index range")));
+        let all = index.range(
+            (1, Reverse(Timestamp::MAX), 0),
+            (1, Reverse(0), ConversationId::MAX),
+        );
+        assert_eq!(2, all.len());
+    }
+
+    fn index_find_should_be_empty_past_the_oldest_entry<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(10);
+        let messages = MessageRepository::with_storage(&storage);
+        let message = messages
+            .insert(Message::builder(1).content("only").build())
+            .unwrap();
+        let conversations = ConversationRepository::with_storage(&storage);
+        conversations.create("only".to_string(), 1).unwrap();
+
+        assert_eq!(
+            vec![message.id],
+            messages.conversation_index.find(1, None, 0)
+        );
+        assert!(messages.conversation_index.find(1, Some(1), 0).is_empty());
+        assert!(messages.conversation_index.find(1, Some(0), 1).is_empty());
+        assert!(conversations.created_index.find(1, Some(0), 0).is_empty());
+        assert!(conversations.user_index.find(1, Some(0), 0).is_empty());
+        assert!(mock_ic0::logs().is_empty());
+    }
+
+    fn first_message_should_be_the_oldest_of_the_conversation<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        assert_eq!(None, repo.first_message(1));
+        let messages = (0..5)
+            .map(|i| {
+                repo.insert(
+                    Message::builder(1 + i % 2)
+                        .content(format!("message {}", i))
+                        .build(),
+                )
+                .unwrap()
+            })
+            .collect_vec();
+
+        assert_eq!(Some(messages[0].clone()), repo.first_message(1));
+        assert_eq!(Some(messages[1].clone()), repo.first_message(2));
+        assert_eq!(None, repo.first_message(3));
+        repo.delete(&messages[0].id).unwrap();
+        assert_eq!(Some(messages[2].clone()), repo.first_message(1));
+    }
+
+    fn id_gaps_should_report_deleted_ids<S: Storage>(storage: S) {
+        let messages = MessageRepository::with_storage(&storage);
+        assert_eq!(GapReport::default(), messages.id_gaps());
+        let ids = (0..8)
+            .map(|i| {
+                messages
+                    .insert(
+                        Message::builder(1)
+                            .content(format!("message {}", i))
+                            .build(),
+                    )
+                    .unwrap()
+                    .id
+            })
+            .collect_vec();
+        for i in [0, 2, 4, 5, 7] {
+            messages.delete(&ids[i]).unwrap();
+        }
+        assert_eq!(
+            GapReport {
+                min: ids[1],
+                max: ids[6],
+                present: 3,
+                gaps: vec![(ids[2], ids[2]), (ids[4], ids[5])],
+            },
+            messages.id_gaps()
+        );
+
+        let users = UserRepository::with_storage(&storage);
+        let ids = (0..3)
+            .map(|i| {
+                users
+                    .upsert_by_principal(
+                        Principal::from_slice(&[i]),
+                        format!("user {}", i),
+                        String::new(),
+                    )
+                    .unwrap()
+                    .id
+            })
+            .collect_vec();
+        users.delete(&ids[1]).unwrap();
+        assert_eq!(
+            GapReport {
+                min: ids[0],
+                max: ids[2],
+                present: 2,
+                gaps: vec![(ids[1], ids[1])],
+            },
+            users.id_gaps()
+        );
+    }
+
+    fn by_id_range_should_list_ascending_ids_across_conversations<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let ids = (0..6)
+            .map(|i| {
+                repo.insert(
+                    Message::builder(1 + i % 3)
+                        .content(format!("message {}", i))
+                        .build(),
+                )
+                .unwrap()
+                .id
+            })
+            .collect_vec();
+        repo.delete(&ids[2]).unwrap();
+
+        let found = |from, to, limit| {
+            repo.by_id_range(from, to, limit)
+                .into_iter()
+                .map(|m| m.id)
+                .collect_vec()
+        };
+        assert_eq!(vec![ids[1], ids[3], ids[4]], found(ids[1], ids[4], 0));
+        assert_eq!(vec![ids[1], ids[3]], found(ids[1], ids[4], 2));
+        assert_eq!(ids[..2].to_vec(), found(0, ids[1], 0));
+        assert_eq!(vec![ids[5]], found(ids[5], MessageId::MAX, 0));
+        assert_eq!(vec![ids[3]], found(ids[3], ids[3], 0));
+        assert!(found(ids[4], ids[1], 0).is_empty());
+    }
+
+    fn message_page_should_skip_previous_pages<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let ids = (0..7)
+            .map(|i| {
+                repo.insert(Message {
+                    id: 0,
+                    conversation: 1,
+                    content: format!("message {}", i),
+                    timestamp: 0,
+                    role: Roles::User,
+                    reply_to: None,
+                })
+                .unwrap()
+                .id
+            })
+            .collect_vec();
+        let page_ids = |page, size| {
+            repo.message_page(1, page, size)
+                .into_iter()
+                .map(|m| m.id)
+                .collect_vec()
+        };
+
+        assert_eq!(vec![ids[6], ids[5], ids[4]], page_ids(0, 3));
+        assert_eq!(vec![ids[3], ids[2], ids[1]], page_ids(1, 3));
+        assert_eq!(vec![ids[0]], page_ids(2, 3));
+        assert!(page_ids(3, 3).is_empty());
+        assert!(page_ids(usize::MAX, 3).is_empty());
+
+        settings::update(|s| s.max_page_size = 2);
+        assert_eq!(vec![ids[4], ids[3]], page_ids(1, 3));
+        assert_eq!(vec![ids[6]], page_ids(0, 0));
+    }
+
+    fn builders_should_leave_ids_to_repositories<S: Storage>(storage: S) {
+        let conversation = ConversationRepository::with_storage(&storage)
+            .insert(Conversation::builder(7).name("Offer").pinned(true).build())
+            .unwrap();
+        assert_eq!(
+            (1, 7, "Offer", true, false),
+            (
+                conversation.id,
+                conversation.user,
+                conversation.name.as_str(),
+                conversation.pinned,
+                conversation.archived
+            )
+        );
+
+        let repo = MessageRepository::with_storage(&storage);
+        let question = repo
+            .insert(
+                Message::builder(conversation.id)
+                    .content("Should I accept?")
+                    .build(),
+            )
+            .unwrap();
+        let answer = repo
+            .insert(
+                Message::builder(conversation.id)
+                    .content("Negotiate first.")
+                    .role(Roles::Assistant)
+                    .reply_to(question.id)
+                    .build(),
+            )
+            .unwrap();
+        assert_eq!(
+            (1, Roles::User, None),
+            (question.id, question.role, question.reply_to)
+        );
+        assert_eq!(
+            (2, Roles::Assistant, Some(question.id)),
+            (answer.id, answer.role, answer.reply_to)
+        );
+        assert_eq!(
+            vec![answer.id],
+            repo.replies(question.id).iter().map(|m| m.id).collect_vec()
+        );
+    }
+
+    fn get_or_err_should_report_missing_entity<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let conversation = repo.create("Offer review".to_string(), 1).unwrap();
+        assert_eq!(Ok(conversation.clone()), repo.get_or_err(&conversation.id));
+        assert_eq!(
+            Err(RepositoryError::NotFound),
+            repo.get_or_err(&(conversation.id + 1))
+        );
+    }
+
+    fn update_message_should_failed<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        repo.update(Message {
+            id: 1,
+            conversation: 1,
+            content: "one".to_string(),
+            timestamp: 0,
+            role: Roles::Assistant,
+            reply_to: None,
+        })
+        .unwrap();
+    }
+
+    fn delete_message_should_work<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        (0..3).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 0,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        repo.delete(&2).unwrap();
+        assert!(repo.get(&2).is_none());
+    }
+
+    fn delete_non_exist_message_should_failed<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        (0..3).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 0,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        repo.delete(&10).unwrap();
+    }
+
+    fn delete_messge_by_conversation_should_work<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        (0..3).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 1,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        (0..5).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 7,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        let (deleted, failed, more) = repo.delete_by_conversation(&1, 0).unwrap();
+        assert_eq!(vec![3, 2, 1], deleted);
+        assert!(failed.is_empty());
+        assert!(!more);
+        assert!(repo.paged_list(1, None, usize::default()).1.is_empty());
+        assert_eq!(5, repo.paged_list(7, None, usize::default()).1.len());
+    }
+
+    fn delete_message_by_conversation_should_report_failures<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        (0..2).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 1,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        // a stale index entry without its message cannot be deleted
+        repo.conversation_index.insert((1, Reverse(99)));
+
+        let (deleted, failed, more) = repo.delete_by_conversation(&1, 0).unwrap();
+        assert_eq!(vec![2, 1], deleted);
+        assert_eq!(vec![99], failed);
+        assert!(!more);
+        assert!(mock_ic0::logs()
+            .iter()
+            .any(|l| l.contains("message 99 of conversation 1")));
+    }
+
+    fn delete_message_by_conversation_should_resume_in_batches<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        (0..25).for_each(|i| {
+            repo.insert(Message {
+                id: 0,
+                conversation: 1 + i % 5 / 4,
+                content: format!("number-{}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        repo.conversation_index.insert((1, Reverse(99)));
+        let mut remaining = repo.conversation_index.find(1, None, 0);
+        assert_eq!(21, remaining.len());
+
+        let mut calls = 0;
+        loop {
+            let (deleted, failed, more) = repo.delete_by_conversation(&1, 8).unwrap();
+            calls += 1;
+            assert_eq!(8.min(remaining.len()), deleted.len() + failed.len());
+            remaining.retain(|id| !deleted.contains(id) && !failed.contains(id));
+            assert_eq!(!remaining.is_empty(), more);
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(3, calls);
+        assert!(repo.conversation_index.find(1, None, 0).is_empty());
+        assert_eq!(5, repo.conversation_index.find(2, None, 0).len());
+    }
+
+    #[test]
+    fn message_should_round_trip_as_version_1() {
+        let message = Message {
+            id: 3,
+            conversation: 1,
+            content: "tagged".to_string(),
+            timestamp: 5,
+            role: Roles::Assistant,
+            reply_to: Some(2),
+        };
+        let bytes = message.to_bytes();
+        assert_eq!(Some(&1), bytes.first());
+        assert_eq!(&bitcode::encode(&message)[..], &bytes[1..]);
+        assert_eq!(message, Message::from_bytes(bytes));
+
+        // records written before the version byte are still readable
+        let untagged = std::borrow::Cow::Owned(bitcode::encode(&message));
+        assert_eq!(message, Message::from_bytes(untagged));
+    }
+
+    #[test]
+    fn tagged_payloads_should_only_decode_when_exact() {
+        let message = Message {
+            id: 1,
+            conversation: 1,
+            content: "tagged".to_string(),
+            timestamp: 1,
+            role: Roles::User,
+            reply_to: None,
+        };
+        let mut payload = bitcode::encode(&message);
+        assert_eq!(None, Message::decode_version(2, &payload));
+        assert_eq!(Some(message), Message::decode_version(1, &payload));
+        payload.push(0);
+        assert_eq!(None, Message::decode_version(1, &payload));
+
+        let conversation = Conversation::builder(1).name("tagged").build();
+        let mut payload = bitcode::encode(&conversation);
+        assert_eq!(
+            Some(conversation),
+            Conversation::decode_version(Conversation::VERSION, &payload)
+        );
+        payload.push(0);
+        assert_eq!(
+            None,
+            Conversation::decode_version(Conversation::VERSION, &payload)
+        );
+    }
+
+    #[test]
+    fn version_1_conversation_should_decode_without_flags() {
+        let legacy = ConversationV1 {
+            id: 1,
+            user: 1,
+            updated_at: 2,
+            name: "tagged".to_string(),
+            created_at: 1,
+        };
+        let mut bytes = vec![1];
+        bytes.extend(bitcode::encode(&legacy));
+        let decoded = Conversation::from_bytes(std::borrow::Cow::Owned(bytes));
+        assert_eq!(("tagged", 1), (decoded.name.as_str(), decoded.created_at));
+        assert!(!decoded.archived && !decoded.pinned);
+
+        let untagged = Conversation::from_bytes(std::borrow::Cow::Owned(bitcode::encode(&legacy)));
+        assert_eq!(decoded, untagged);
+
+        let current = Conversation {
+            pinned: true,
+            ..decoded
+        };
+        let bytes = current.to_bytes();
+        assert_eq!(Some(&4), bytes.first());
+        assert_eq!(current, Conversation::from_bytes(bytes));
+    }
+
+    #[test]
+    fn version_2_conversation_should_decode_without_deletion() {
+        let legacy = ConversationV2 {
+            id: 1,
+            user: 1,
+            updated_at: 2,
+            name: "archived".to_string(),
+            created_at: 1,
+            archived: true,
+            pinned: false,
+        };
+        let mut bytes = vec![2];
+        bytes.extend(bitcode::encode(&legacy));
+        let decoded = Conversation::from_bytes(std::borrow::Cow::Owned(bytes));
+        assert!(decoded.archived);
+        assert_eq!(None, decoded.deleted_at);
+    }
 
-        let user = User {
+    #[test]
+    fn version_3_conversation_should_decode_unstarred() {
+        let legacy = ConversationV3 {
             id: 1,
-            fullname: "test_user".to_string(),
-            identity: Principal::anonymous(),
-            resume: "engineer".to_string(),
+            user: 1,
+            updated_at: 2,
+            name: "trashed".to_string(),
+            created_at: 1,
+            archived: false,
+            pinned: true,
+            deleted_at: Some(3),
         };
-        let encoded_user = user.to_bytes();
-        let decoded_user = User::from_bytes(encoded_user);
-        assert_eq!(user, decoded_user);
+        let mut bytes = vec![3];
+        bytes.extend(bitcode::encode(&legacy));
+        let decoded = Conversation::from_bytes(std::borrow::Cow::Owned(bytes));
+        assert!(decoded.pinned && !decoded.starred);
+        assert_eq!(Some(3), decoded.deleted_at);
     }
 
     #[test]
-    fn generated_id_should_consistent() {
-        reset_msg_data();
-        reset_conv_data();
-        let msg_repo = MessageRepository::default();
-        let con_repo = ConversationRepository::default();
+    fn legacy_conversation_settings_should_decode_without_persona() {
+        let legacy = ConversationSettingsV0 {
+            model: Some("qwen3:32b".to_string()),
+            temperature: Some(0.5),
+            system_prompt: None,
+        };
+        let decoded =
+            ConversationSettings::from_bytes(std::borrow::Cow::Owned(bitcode::encode(&legacy)));
+        assert_eq!(
+            ConversationSettings {
+                model: Some("qwen3:32b".to_string()),
+                temperature: Some(0.5),
+                system_prompt: None,
+                persona: None,
+                max_tokens: None,
+            },
+            decoded
+        );
 
-        assert_eq!(msg_repo.peek_next_id(), 1);
-        assert_eq!(con_repo.peek_next_id(), 1);
+        let current = ConversationSettings {
+            persona: Some(Persona::InterviewCoach),
+            ..decoded
+        };
+        let bytes = current.to_bytes();
+        assert_eq!(Some(&2), bytes.first());
+        assert_eq!(current, ConversationSettings::from_bytes(bytes));
+    }
 
-        assert_eq!(msg_repo.next_id(), 1);
-        assert_eq!(msg_repo.peek_next_id(), 2);
-        assert_eq!(con_repo.next_id(), 1);
-        assert_eq!(con_repo.peek_next_id(), 2);
-        assert_eq!(con_repo.next_id(), 2);
-        assert_eq!(con_repo.peek_next_id(), 3);
+    #[test]
+    fn legacy_conversation_settings_starting_with_a_version_should_decode_as_legacy() {
+        let candidates = (0..64).flat_map(|len| {
+            [None, Some(0.5)]
+                .into_iter()
+                .map(move |temperature| ConversationSettingsV0 {
+                    model: Some("m".repeat(len)),
+                    temperature,
+                    system_prompt: (len % 2 == 0).then(|| "p".repeat(len)),
+                })
+        });
+        let mut checked = 0;
+        for legacy in candidates {
+            let bytes = bitcode::encode(&legacy);
+            if !matches!(bytes.first(), Some(1 | 2)) {
+                continue;
+            }
+            checked += 1;
+            assert_eq!(
+                ConversationSettings::from(legacy),
+                ConversationSettings::from_bytes(std::borrow::Cow::Owned(bytes))
+            );
+        }
+        assert!(checked > 0);
     }
 
     #[test]
-    fn get_and_insert_message_should_work() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
-        repo.insert(Message {
-            id: 123,
-            conversation: 1,
-            content: "Hi World!".to_string(),
-            timestamp: 123,
-            role: Roles::User,
-        })
-        .unwrap();
-        assert!(repo.get(&123).is_none());
-        assert!(repo.get(&1).is_some());
-        assert_eq!("Hi World!".to_string(), repo.get(&1).unwrap().content)
+    fn v1_conversation_settings_should_decode_without_max_tokens() {
+        let legacy = ConversationSettingsV1 {
+            model: None,
+            temperature: Some(0.1),
+            system_prompt: None,
+            persona: Some(Persona::InterviewCoach),
+        };
+        let mut bytes = vec![1];
+        bytes.extend(bitcode::encode(&legacy));
+        assert_eq!(
+            ConversationSettings {
+                temperature: Some(0.1),
+                persona: Some(Persona::InterviewCoach),
+                ..Default::default()
+            },
+            ConversationSettings::from_bytes(std::borrow::Cow::Owned(bytes))
+        );
+    }
+
+    fn keys<K, V>(map: &impl Store<K, V>) -> Vec<K> {
+        map.walk(.., |entries| entries.map(|(k, _)| k).collect())
+    }
+
+    fn batched_reindex_should_match_full_reindex<S: Storage>(storage: S) {
+        let messages = MessageRepository::with_storage(&storage);
+        let conversations = ConversationRepository::with_storage(&storage);
+        for i in 1..=7 {
+            conversations
+                .create(format!("Conversation {}", i), i % 3)
+                .unwrap();
+            messages
+                .insert(Message {
+                    id: 0,
+                    conversation: i % 2,
+                    content: format!("Message {}", i),
+                    timestamp: 0,
+                    role: Roles::User,
+                    reply_to: (i > 2).then(|| i - 2),
+                })
+                .unwrap();
+        }
+        let snapshot = || {
+            (
+                keys(&storage.message_conversation_index()),
+                keys(&storage.message_reply_index()),
+                keys(&storage.conversation_user_index()),
+                keys(&storage.conversation_created_index()),
+                keys(&storage.conversation_stale_index()),
+            )
+        };
+        messages.reindex();
+        conversations.reindex();
+        let full = snapshot();
+        assert_eq!(7, full.0.len());
+
+        messages.reply_index.insert((99, Reverse(98)));
+        conversations.user_index.insert((42, Reverse(0), 98));
+        let mut progress = vec![];
+        let mut from = 0;
+        loop {
+            let step = messages.reindex_batch(from, 3);
+            progress.push(step.clone());
+            from = step.next;
+            if step.done {
+                break;
+            }
+        }
+        assert_eq!(
+            vec![(3, false), (3, false), (1, true)],
+            progress.iter().map(|p| (p.processed, p.done)).collect_vec()
+        );
+        let first = conversations.reindex_batch(0, 4);
+        assert_eq!((4, false), (first.processed, first.done));
+        let last = conversations.reindex_batch(first.next, 4);
+        assert_eq!((3, true), (last.processed, last.done));
+        assert!(conversations.reindex_batch(last.next, 4).done);
+        assert_eq!(full, snapshot());
     }
 
     #[test]
-    #[should_panic]
-    fn update_message_should_failed() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
-        repo.update(Message {
+    fn legacy_message_should_decode_without_reply() {
+        let legacy = MessageV0 {
             id: 1,
             conversation: 1,
-            content: "one".to_string(),
-            timestamp: 0,
-            role: Roles::Assistant,
-        })
-        .unwrap();
+            content: "old".to_string(),
+            timestamp: 1,
+            role: Roles::User,
+        };
+        let decoded = Message::from_bytes(std::borrow::Cow::Owned(bitcode::encode(&legacy)));
+        assert_eq!("old", decoded.content);
+        assert_eq!(None, decoded.reply_to);
     }
 
-    #[test]
-    fn delete_message_should_work() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
-        (0..3).for_each(|i| {
-            repo.insert(Message {
+    fn reply_and_list_replies_should_work<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let parent = repo
+            .insert(Message {
                 id: 0,
-                conversation: 0,
-                content: format!("number-{}", i),
+                conversation: 1,
+                content: "question".to_string(),
                 timestamp: 0,
                 role: Roles::User,
+                reply_to: None,
             })
             .unwrap();
-        });
-        repo.delete(&2).unwrap();
-        assert!(repo.get(&2).is_none());
-    }
-
-    #[test]
-    #[should_panic]
-    fn delete_non_exist_message_should_failed() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
-        (0..3).for_each(|i| {
+        (0..2).for_each(|i| {
             repo.insert(Message {
                 id: 0,
-                conversation: 0,
-                content: format!("number-{}", i),
+                conversation: 1,
+                content: format!("reply-{}", i),
                 timestamp: 0,
-                role: Roles::User,
+                role: Roles::Assistant,
+                reply_to: Some(parent.id),
             })
             .unwrap();
         });
-        repo.delete(&10).unwrap();
+        repo.insert(Message {
+            id: 0,
+            conversation: 1,
+            content: "unrelated".to_string(),
+            timestamp: 0,
+            role: Roles::User,
+            reply_to: None,
+        })
+        .unwrap();
+
+        let replies = repo.replies(parent.id);
+        assert_eq!(replies.iter().map(|m| m.id).collect_vec(), vec![3, 2]);
+        assert!(repo.replies(4).is_empty());
     }
 
-    #[test]
-    fn delete_messge_by_conversation_should_work() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
-        (0..3).for_each(|i| {
-            repo.insert(Message {
+    fn reply_across_conversation_should_failed<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let parent = repo
+            .insert(Message {
                 id: 0,
                 conversation: 1,
-                content: format!("number-{}", i),
-                timestamp: 0,
-                role: Roles::User,
-            })
-            .unwrap();
-        });
-        (0..5).for_each(|i| {
-            repo.insert(Message {
-                id: 0,
-                conversation: 7,
-                content: format!("number-{}", i),
+                content: "question".to_string(),
                 timestamp: 0,
                 role: Roles::User,
+                reply_to: None,
             })
             .unwrap();
+        let result = repo.insert(Message {
+            id: 0,
+            conversation: 2,
+            content: "reply".to_string(),
+            timestamp: 0,
+            role: Roles::User,
+            reply_to: Some(parent.id),
         });
-        repo.delete_by_conversation(&1).unwrap();
-        assert!(repo.paged_list(1, None, usize::default()).1.is_empty());
-        assert_eq!(5, repo.paged_list(7, None, usize::default()).1.len());
+        assert!(matches!(
+            result,
+            Err(RepositoryError::InvalidReference { .. })
+        ));
+        assert_eq!(2, repo.peek_next_id());
+        assert!(repo.replies(parent.id).is_empty());
     }
 
-    #[test]
-    fn message_cursor_paged_list_should_return_correct_list() {
-        reset_msg_data();
-        let repo = MessageRepository::default();
+    fn message_cursor_paged_list_should_return_correct_list<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
 
         // 1-5 for conv 1
         for i in 1..=5 {
@@ -948,6 +5677,7 @@ mod tests {
                 content: format!("Message {}", i),
                 timestamp: i,
                 role: Roles::User,
+                reply_to: None,
             })
             .unwrap();
         }
@@ -959,6 +5689,7 @@ mod tests {
                 content: format!("Message {}", i),
                 timestamp: 2,
                 role: Roles::User,
+                reply_to: None,
             })
             .unwrap();
         }
@@ -969,6 +5700,7 @@ mod tests {
             content: format!("Message {}", 10),
             timestamp: 10,
             role: Roles::User,
+            reply_to: None,
         })
         .unwrap();
 
@@ -992,32 +5724,437 @@ mod tests {
         assert_eq!(conv2.iter().map(|m| m.id).collect::<Vec<_>>(), vec![7, 6]);
     }
 
-    #[test]
-    fn get_and_upsert_conversation_should_work() {
-        reset_conv_data();
-        let repo = ConversationRepository::default();
-        let mut conversation = Conversation {
-            id: 1,
-            user: 1,
-            updated_at: 1234567890,
-            name: "Test Conversation".to_string(),
-        };
-        repo.upsert(conversation.clone()).unwrap();
-        assert!(repo.get(&1).is_some());
-        assert_eq!("Test Conversation", repo.get(&1).unwrap().name);
-        conversation.name = "Updated Conversation".to_string();
-        repo.upsert(conversation).unwrap();
-        assert_eq!("Updated Conversation", repo.get(&1).unwrap().name);
+    fn message_paged_list_should_not_skip_after_deletions<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        for i in 1..=8 {
+            repo.insert(Message {
+                id: 0,
+                conversation: 1,
+                content: format!("Message {}", i),
+                timestamp: i,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        }
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect_vec();
+
+        let (cursor, page1) = repo.paged_list(1, None, 3);
+        assert_eq!(vec![8, 7, 6], ids(page1));
+        // the last seen row and a row of the next page are deleted in between
+        repo.delete(&6).unwrap();
+        repo.delete(&4).unwrap();
+        let (cursor, page2) = repo.paged_list(1, cursor, 3);
+        assert_eq!(vec![5, 3, 2], ids(page2));
+        let (cursor, page3) = repo.paged_list(1, cursor, 3);
+        assert_eq!(vec![1], ids(page3));
+        assert_eq!((None, vec![]), repo.paged_list(1, cursor, 3));
+    }
+
+    fn message_paged_list_cursor_should_pass_missing_messages<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        for i in 1..=3 {
+            repo.insert(Message {
+                id: 0,
+                conversation: 1,
+                content: format!("Message {}", i),
+                timestamp: i,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        }
+        // a whole page of index entries without messages used to end the scroll with no cursor
+        repo.conversation_index.insert((1, Reverse(9)));
+        repo.conversation_index.insert((1, Reverse(8)));
+
+        let (cursor, page1) = repo.paged_list(1, None, 2);
+        assert!(page1.is_empty());
+        assert_eq!(Some(8), cursor);
+        let (_, page2) = repo.paged_list(1, cursor, 2);
+        assert_eq!(vec![3, 2], page2.iter().map(|m| m.id).collect_vec());
+    }
+
+    fn messages_around_should_clamp_at_conversation_boundaries<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        for i in 1..=7 {
+            repo.insert(Message {
+                id: 0,
+                conversation: if i == 4 { 2 } else { 1 },
+                content: format!("Message {}", i),
+                timestamp: i,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        }
+        let around = |pivot, before, after| {
+            repo.messages_around(1, pivot, before, after)
+                .map(|messages| messages.iter().map(|m| m.id).collect_vec())
+        };
+
+        // start of the conversation, nothing older than the first message
+        assert_eq!(Ok(vec![3, 2, 1]), around(1, 2, 2));
+        // middle, message 4 belongs to another conversation
+        assert_eq!(Ok(vec![6, 5, 3, 2]), around(3, 1, 2));
+        assert_eq!(Ok(vec![5, 3]), around(5, 1, 0));
+        // end of the conversation, nothing newer than the last message
+        assert_eq!(Ok(vec![7, 6, 5]), around(7, 2, 2));
+        assert_eq!(Err(RepositoryError::NotFound), around(4, 1, 1));
+    }
+
+    fn search_messages_should_match_every_term_of_the_query<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        [
+            (1, "How do I negotiate my salary?"),
+            (1, "Salary expectations for a junior role"),
+            (2, "Negotiate the salary, then the title"),
+            (1, "Salary negotiation, again: NEGOTIATE salary"),
+        ]
+        .into_iter()
+        .for_each(|(conversation, content)| {
+            repo.insert(Message {
+                id: 0,
+                conversation,
+                content: content.to_string(),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        });
+        let search = |query, limit| {
+            repo.search_messages(1, query, limit, SearchOrder::Newest)
+                .iter()
+                .map(|m| m.id)
+                .collect_vec()
+        };
+
+        assert_eq!(vec![4, 2, 1], search("salary", 0));
+        assert_eq!(vec![4, 1], search("Negotiate SALARY", 0));
+        assert_eq!(vec![4], search("negotiate salary", 1));
+        assert!(search("interview", 0).is_empty());
+        assert!(search("?!", 0).is_empty());
+
+        repo.delete(&4).unwrap();
+        assert_eq!(vec![1], search("negotiate", 0));
+    }
+
+    fn search_page_should_scroll_without_duplicates<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        let insert = |conversation, content: &str| {
+            repo.insert(Message::builder(conversation).content(content).build())
+                .unwrap()
+                .id
+        };
+        (1..=7).for_each(|i| {
+            insert(1, &format!("Resume tip {}", i));
+            insert(1, "Unrelated");
+            insert(2, "Resume tip elsewhere");
+        });
+        let page = |cursor, limit| {
+            let (next, messages) = repo.search_page(1, "resume tip", cursor, limit);
+            (next, messages.iter().map(|m| m.id).collect_vec())
+        };
+
+        let (cursor, first) = page(None, 3);
+        assert_eq!(vec![19, 16, 13], first);
+        insert(1, "Resume tip, inserted while scrolling");
+        let (cursor, second) = page(cursor, 3);
+        assert_eq!(vec![10, 7, 4], second);
+        let (cursor, third) = page(cursor, 3);
+        assert_eq!((Some(1), vec![1]), (cursor, third.clone()));
+        assert_eq!((None, vec![]), page(cursor, 3));
+
+        let scrolled = [first, second, third].concat();
+        assert_eq!(7, scrolled.iter().unique().count());
+        assert_eq!(vec![22, 19], page(None, 2).1);
+        assert_eq!((None, vec![]), repo.search_page(1, "?!", None, 0));
+    }
+
+    fn search_messages_by_relevance_should_favor_term_frequency<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        [
+            "Salary first, salary last, always salary",
+            "Ask about the salary",
+            "The salary range and the salary review",
+            "Salary bands",
+        ]
+        .into_iter()
+        .for_each(|content| {
+            repo.insert(Message::builder(1).content(content).build())
+                .unwrap();
+        });
+        let search = |order, limit| {
+            repo.search_messages(1, "salary", limit, order)
+                .iter()
+                .map(|m| m.id)
+                .collect_vec()
+        };
+
+        assert_eq!(vec![4, 3, 2, 1], search(SearchOrder::Newest, 0));
+        assert_eq!(vec![1, 3, 4, 2], search(SearchOrder::Relevance, 0));
+        assert_eq!(vec![1, 3], search(SearchOrder::Relevance, 2));
+        assert_eq!(vec![4, 3], search(SearchOrder::Newest, 2));
+    }
+
+    fn recent_messages_should_page_across_conversations<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(100);
+        let repo = MessageRepository::with_storage(&storage);
+        for i in 1..=5 {
+            repo.insert(Message {
+                id: 0,
+                conversation: i % 2 + 1,
+                content: format!("Message {}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        }
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect_vec();
+
+        let (cursor, page1) = repo.recent(None, 2);
+        assert_eq!(vec![5, 4], ids(page1));
+        assert_eq!(Some((103, 4)), cursor);
+        repo.delete(&3).unwrap();
+        let (cursor, page2) = repo.recent(cursor, 2);
+        assert_eq!(vec![2, 1], ids(page2));
+        assert_eq!((None, vec![]), repo.recent(cursor, 2));
+        assert_eq!(vec![5, 4, 2, 1], ids(repo.recent(None, 0).1));
+    }
+
+    fn recent_messages_should_tie_break_equal_timestamps_by_id<S: Storage>(storage: S) {
+        let repo = MessageRepository::with_storage(&storage);
+        for i in 1..=5 {
+            mock_ic0::reset_timestamp_to(if i == 1 { 10 } else { 20 });
+            repo.insert(Message {
+                id: 0,
+                conversation: i % 2 + 1,
+                content: format!("Message {}", i),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        }
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect_vec();
+
+        assert_eq!(vec![5, 4, 3, 2, 1], ids(repo.recent(None, 0).1));
+        let (cursor, page1) = repo.recent(None, 2);
+        assert_eq!((Some((20, 4)), vec![5, 4]), (cursor, ids(page1)));
+        let (cursor, page2) = repo.recent(cursor, 2);
+        assert_eq!((Some((20, 2)), vec![3, 2]), (cursor, ids(page2)));
+        let (cursor, page3) = repo.recent(cursor, 2);
+        assert_eq!((Some((10, 1)), vec![1]), (cursor, ids(page3)));
+    }
+
+    fn get_and_upsert_conversation_should_work<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let mut conversation = Conversation {
+            id: 1,
+            user: 1,
+            updated_at: 1234567890,
+            created_at: 0,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
+            name: "Test Conversation".to_string(),
+        };
+        assert!(repo.upsert(conversation.clone()).unwrap().created);
+        assert!(repo.get(&1).is_some());
+        assert_eq!("Test Conversation", repo.get(&1).unwrap().name);
+        conversation.name = "Updated Conversation".to_string();
+        let outcome = repo.upsert(conversation.clone()).unwrap();
+        assert!(!outcome.created);
+        assert_eq!("Updated Conversation", outcome.conversation.name);
+        assert_eq!("Updated Conversation", repo.get(&1).unwrap().name);
+        assert!(!repo.upsert(conversation).unwrap().created);
+    }
+
+    fn upsert_new_conversation_should_always_create<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let first = repo.create("first".to_string(), 1).unwrap();
+        assert_eq!(1, first.id);
+
+        let second = repo
+            .upsert(Conversation {
+                id: NEW_ENTITY_ID,
+                user: 1,
+                updated_at: 0,
+                name: "second".to_string(),
+                created_at: 0,
+                archived: false,
+                pinned: false,
+                starred: false,
+                deleted_at: None,
+            })
+            .unwrap();
+        assert!(second.created);
+        let second = second.conversation;
+        assert_eq!(2, second.id);
+        assert_eq!("first", repo.get(&1).unwrap().name);
+
+        let updated = repo
+            .upsert(Conversation {
+                name: "renamed".to_string(),
+                ..first
+            })
+            .unwrap();
+        assert!(!updated.created);
+        let updated = updated.conversation;
+        assert_eq!(1, updated.id);
+        assert_eq!("renamed", repo.get(&1).unwrap().name);
+        assert_eq!(3, repo.peek_next_id());
+    }
+
+    fn archive_inactive_should_only_archive_eligible_conversations<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(1);
+        let repo = ConversationRepository::with_storage(&storage);
+        // updated at 1 to 5, for two different users
+        let ids = (1..=5)
+            .map(|i| {
+                repo.create(format!("Conversation {}", i), i % 2)
+                    .unwrap()
+                    .id
+            })
+            .collect_vec();
+        repo.set_pinned(ids[1], true).unwrap();
+        repo.set_archived(ids[2], true).unwrap();
+        assert_eq!(3, repo.get(&ids[2]).unwrap().updated_at);
+
+        let archived = repo.archive_inactive(5);
+        assert_eq!(vec![ids[0], ids[3]], archived);
+        let states = ids
+            .iter()
+            .map(|id| repo.get(id).unwrap())
+            .map(|c| (c.archived, c.pinned))
+            .collect_vec();
+        assert_eq!(
+            vec![
+                (true, false),
+                (false, true),
+                (true, false),
+                (true, false),
+                (false, false)
+            ],
+            states
+        );
+        assert_eq!(4, repo.get(&ids[3]).unwrap().updated_at);
+        assert!(repo.archive_inactive(5).is_empty());
+    }
+
+    fn stale_index_should_page_oldest_first<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(1);
+        let repo = ConversationRepository::with_storage(&storage);
+        (1..=4).for_each(|i| {
+            repo.create(format!("Conversation {}", i), 1).unwrap();
+        });
+        let first = repo.get(&1).unwrap();
+        repo.update(first).unwrap();
+
+        assert_eq!(vec![2, 3], repo.stale_index.find(10, None, 2));
+        assert_eq!(vec![4, 1], repo.stale_index.find(10, Some((3, 3)), 0));
+        assert_eq!(vec![2, 3, 4], repo.stale_index.find(5, None, 0));
+    }
+
+    fn update_conversation_should_reject_owner_change<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let conversation = repo.create("mine".to_string(), 1).unwrap();
+        assert_eq!(
+            Err(RepositoryError::OwnershipMismatch {
+                id: conversation.id
+            }),
+            repo.update(Conversation {
+                user: 2,
+                ..conversation.clone()
+            })
+        );
+        assert_eq!(1, repo.get(&conversation.id).unwrap().user);
+    }
+
+    #[derive(Default, Debug)]
+    struct RecordingSink {
+        created: Mutex<Vec<Conversation>>,
+    }
+
+    impl ConversationEventSink for RecordingSink {
+        fn on_created(&self, conversation: &Conversation) {
+            self.created.lock().unwrap().push(conversation.clone());
+        }
+    }
+
+    fn insert_conversation_should_notify_event_sink<S: Storage>(storage: S) {
+        let sink = Arc::new(RecordingSink::default());
+        let repo = ConversationRepository::with_storage(&storage).with_event_sink(sink.clone());
+        let created = repo.create("tracked".to_string(), 1).unwrap();
+        repo.update(Conversation {
+            name: "renamed".to_string(),
+            ..created.clone()
+        })
+        .unwrap();
+        assert_eq!(vec![created], *sink.created.lock().unwrap());
+
+        ConversationRepository::with_storage(&storage)
+            .create("untracked".to_string(), 1)
+            .unwrap();
+        assert_eq!(1, sink.created.lock().unwrap().len());
+    }
+
+    fn most_recent_conversation_should_skip_archived_ones<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        assert_eq!(None, repo.most_recent_conversation(1));
+
+        let older = repo.create("older".to_string(), 1).unwrap();
+        repo.set_archived(older.id, true).unwrap();
+        assert_eq!(None, repo.most_recent_conversation(1));
+
+        let active = repo.create("active".to_string(), 1).unwrap();
+        let newest = repo.create("newest".to_string(), 1).unwrap();
+        repo.create("theirs".to_string(), 2).unwrap();
+        assert_eq!(Some(newest.clone()), repo.most_recent_conversation(1));
+        let archived = repo.set_archived(newest.id, true).unwrap();
+        assert_eq!(Some(active.clone()), repo.most_recent_conversation(1));
+        let bumped = repo.update(repo.get(&older.id).unwrap()).unwrap();
+        assert!(bumped.updated_at > archived.updated_at);
+        assert_eq!(Some(active), repo.most_recent_conversation(1));
+    }
+
+    fn trashed_conversation_should_only_be_in_trash_index<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let kept = repo.create("kept".to_string(), 1).unwrap();
+        let trashed = repo.create("trashed".to_string(), 1).unwrap();
+
+        let in_trash = repo.trash(trashed.id).unwrap();
+        assert!(in_trash.deleted_at.is_some());
+        assert_eq!(trashed.updated_at, in_trash.updated_at);
+        assert_eq!(vec![kept.id], repo.user_index.find(1, None, 0));
+        assert_eq!(vec![kept.id], repo.created_index.find(1, None, 0));
+        assert_eq!(
+            vec![kept.id],
+            repo.stale_index.find(Timestamp::MAX, None, 0)
+        );
+        assert_eq!(vec![trashed.id], repo.trash_index.find(1, None, 0));
+        assert_eq!(1, repo.count_by_user(1));
+
+        assert_eq!(Ok(trashed.clone()), repo.restore(trashed.id));
+        assert!(repo.trash_index.find(1, None, 0).is_empty());
+        assert_eq!(vec![trashed.id, kept.id], repo.user_index.find(1, None, 0));
+        assert_eq!(Err(RepositoryError::NotFound), repo.trash(trashed.id + 1));
     }
 
-    #[test]
-    fn delete_conversation_should_work() {
-        reset_conv_data();
-        let repo = ConversationRepository::default();
+    fn delete_conversation_should_work<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
         repo.insert(Conversation {
             id: 0,
             user: 1,
             updated_at: 0,
+            created_at: 0,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
             name: String::from("abc"),
         })
         .unwrap();
@@ -1026,15 +6163,64 @@ mod tests {
         assert!(repo.get(&1).is_none());
     }
 
-    #[test]
-    #[should_panic]
-    fn delete_non_exist_conversation_should_failed() {
-        reset_conv_data();
-        let repo = ConversationRepository::default();
+    fn delete_should_leave_tombstones_for_the_owner<S: Storage>(storage: S) {
+        let convs = ConversationRepository::with_storage(&storage);
+        let conv = convs.create("chat".to_string(), 7).unwrap();
+        let msgs = MessageRepository::with_storage(&storage);
+        let message = msgs
+            .insert(Message {
+                id: 0,
+                conversation: conv.id,
+                content: "hello".to_string(),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+
+        mock_ic0::reset_timestamp_to(50);
+        msgs.delete(&message.id).unwrap();
+        convs.delete(&conv.id).unwrap();
+
+        assert_eq!(vec![message.id], msgs.tombstones.since(7, 50));
+        assert_eq!(vec![conv.id], convs.tombstones.since(7, 50));
+        // every call of the mocked clock ticks, the conversation went one tick after the message
+        assert!(msgs.tombstones.since(7, 51).is_empty());
+        assert!(convs.tombstones.since(7, 52).is_empty());
+        assert!(convs.tombstones.since(8, 0).is_empty());
+    }
+
+    fn tombstones_should_be_pruned_before_the_horizon<S: Storage>(storage: S) {
+        let msgs = MessageRepository::with_storage(&storage);
+        let convs = ConversationRepository::with_storage(&storage);
+        mock_ic0::reset_timestamp_to(10);
+        msgs.tombstones.record(7, 1);
+        msgs.tombstones.record(8, 2);
+        convs.tombstones.record(7, 3);
+        mock_ic0::reset_timestamp_to(100);
+        msgs.tombstones.record(7, 4);
+        convs.tombstones.record(8, 5);
+
+        assert_eq!(2, msgs.tombstones.prune_before(100));
+        assert_eq!(1, convs.tombstones.prune_before(100));
+        assert_eq!(vec![4], msgs.tombstones.since(7, 0));
+        assert!(msgs.tombstones.since(8, 0).is_empty());
+        assert!(convs.tombstones.since(7, 0).is_empty());
+        assert_eq!(vec![5], convs.tombstones.since(8, 0));
+        assert_eq!(0, msgs.tombstones.prune_before(100));
+    }
+
+    fn delete_non_exist_conversation_should_failed<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
         repo.insert(Conversation {
             id: 0,
             user: 1,
             updated_at: 0,
+            created_at: 0,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
             name: String::from("abc"),
         })
         .unwrap();
@@ -1042,11 +6228,9 @@ mod tests {
         repo.delete(&3).unwrap();
     }
 
-    #[test]
-    fn conversation_cursor_paged_list_should_return_correct_list() {
-        reset_conv_data();
+    fn conversation_cursor_paged_list_should_return_correct_list<S: Storage>(storage: S) {
         mock_ic0::reset_timestamp_to(1);
-        let repo = ConversationRepository::default();
+        let repo = ConversationRepository::with_storage(&storage);
 
         // 1-5 for user 1
         for i in 1..=5 {
@@ -1054,6 +6238,11 @@ mod tests {
                 id: i,
                 user: 1,
                 updated_at: i,
+                created_at: 0,
+                archived: false,
+                pinned: false,
+                starred: false,
+                deleted_at: None,
                 name: format!("Conversation {}", i),
             })
             .unwrap();
@@ -1064,6 +6253,11 @@ mod tests {
                 id: i,
                 user: 2,
                 updated_at: i,
+                created_at: 0,
+                archived: false,
+                pinned: false,
+                starred: false,
+                deleted_at: None,
                 name: format!("Conversation {}", i),
             })
             .unwrap();
@@ -1073,12 +6267,17 @@ mod tests {
             id: 10,
             user: 1,
             updated_at: 10,
+            created_at: 0,
+            archived: false,
+            pinned: false,
+            starred: false,
+            deleted_at: None,
             name: format!("Conversation {}", 10),
         })
         .unwrap();
 
         // Initial load (latest 3 conversations for user 1)
-        let (next_cursor, page1) = repo.paged_list(1, None, 3);
+        let (next_cursor, page1) = repo.paged_list(1, None, 3, SortDir::Desc, true);
         assert_eq!(
             page1.iter().map(|c| c.id).collect::<Vec<_>>(),
             vec![10, 5, 4]
@@ -1086,21 +6285,369 @@ mod tests {
         assert_eq!(next_cursor.unwrap(), 4);
 
         // Scroll up (older than 10)
-        let (_, page2) = repo.paged_list(1, Some(10), 3);
+        let (_, page2) = repo.paged_list(1, Some(10), 3, SortDir::Desc, true);
         assert_eq!(
             page2.iter().map(|c| c.id).collect::<Vec<_>>(),
             vec![5, 4, 3]
         );
 
         // user 2 out of limit
-        let (_, user2) = repo.paged_list(2, Some(8), 5);
+        let (_, user2) = repo.paged_list(2, Some(8), 5, SortDir::Desc, true);
         assert_eq!(user2.iter().map(|c| c.id).collect::<Vec<_>>(), vec![7, 6]);
     }
 
+    fn conversation_paged_list_should_follow_direction<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(10);
+        let repo = ConversationRepository::with_storage(&storage);
+        // updated at 10 to 14, conversation 6 belongs to someone else
+        for i in 1..=6 {
+            repo.create(format!("Conversation {}", i), 1 + i / 6)
+                .unwrap();
+        }
+        let ids = |conv: Vec<Conversation>| conv.iter().map(|c| c.id).collect_vec();
+
+        let (cursor, page1) = repo.paged_list(1, None, 2, SortDir::Desc, true);
+        assert_eq!(vec![5, 4], ids(page1));
+        assert_eq!(Some(13), cursor);
+        let (cursor, page2) = repo.paged_list(1, cursor, 2, SortDir::Desc, true);
+        assert_eq!(vec![3, 2], ids(page2));
+        let (cursor, page3) = repo.paged_list(1, cursor, 2, SortDir::Desc, true);
+        assert_eq!(vec![1], ids(page3));
+        assert_eq!(Some(10), cursor);
+
+        let (cursor, page1) = repo.paged_list(1, None, 2, SortDir::Asc, true);
+        assert_eq!(vec![1, 2], ids(page1));
+        assert_eq!(Some(11), cursor);
+        let (cursor, page2) = repo.paged_list(1, cursor, 2, SortDir::Asc, true);
+        assert_eq!(vec![3, 4], ids(page2));
+        let (cursor, page3) = repo.paged_list(1, cursor, 2, SortDir::Asc, true);
+        assert_eq!(vec![5], ids(page3));
+        assert_eq!(
+            (None, vec![]),
+            repo.paged_list(1, cursor, 2, SortDir::Asc, true)
+        );
+    }
+
+    fn conversation_name_should_pass_filter_when_enabled<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        settings::update(|s| s.blocked_words = vec!["darn".to_string()]);
+        let kept = repo.create("Darn interviews".to_string(), 1).unwrap();
+
+        settings::update(|s| s.filter_conversation_names = true);
+        let allowed = repo.create("Interview prep".to_string(), 1).unwrap();
+        assert_eq!(
+            Err(RepositoryError::InvalidData {
+                reason: "conversation name is not allowed".to_string()
+            }),
+            repo.create("Darn interviews".to_string(), 1)
+        );
+        assert_eq!(2, repo.count_by_user(1));
+        assert_eq!(3, repo.peek_next_id());
+
+        assert!(repo
+            .update(Conversation {
+                name: "this DARN offer".to_string(),
+                ..allowed.clone()
+            })
+            .is_err());
+        assert_eq!(Some(allowed), repo.get(&2));
+        assert!(repo.set_pinned(kept.id, true).is_ok());
+    }
+
+    fn set_pinned_should_stop_at_pin_limit<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        settings::update(|s| s.max_pinned = 2);
+        let ids = (0..3)
+            .map(|i| repo.create(format!("Conversation {}", i), 1).unwrap().id)
+            .collect_vec();
+        let theirs = repo.create("Theirs".to_string(), 2).unwrap();
+
+        assert!(repo.set_pinned(ids[0], true).is_ok());
+        assert!(repo.set_pinned(ids[1], true).is_ok());
+        assert!(repo.set_pinned(ids[1], true).is_ok());
+        assert_eq!(
+            Err(RepositoryError::QuotaExceeded { limit: 2 }),
+            repo.set_pinned(ids[2], true)
+        );
+        assert!(!repo.get(&ids[2]).unwrap().pinned);
+        assert!(repo.set_pinned(theirs.id, true).is_ok());
+
+        repo.set_pinned(ids[0], false).unwrap();
+        assert!(repo.set_pinned(ids[2], true).unwrap().pinned);
+        assert_eq!(2, repo.pinned_count(1));
+        repo.reindex();
+        assert_eq!(2, repo.pinned_count(1));
+    }
+
+    fn trashed_pin_should_keep_its_slot<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        settings::update(|s| s.max_pinned = 1);
+        let pinned = repo.create("Pinned".to_string(), 1).unwrap();
+        let other = repo.create("Other".to_string(), 1).unwrap();
+        repo.set_pinned(pinned.id, true).unwrap();
+
+        repo.trash(pinned.id).unwrap();
+        assert_eq!(
+            Err(RepositoryError::QuotaExceeded { limit: 1 }),
+            repo.set_pinned(other.id, true)
+        );
+        assert!(repo.restore(pinned.id).unwrap().pinned);
+        assert_eq!(1, repo.pinned_count(1));
+
+        repo.trash(pinned.id).unwrap();
+        repo.delete(&pinned.id).unwrap();
+        assert_eq!(0, repo.pinned_count(1));
+        assert!(repo.set_pinned(other.id, true).is_ok());
+    }
+
     #[test]
-    fn get_and_insert_user_should_work() {
-        reset_user_data();
-        let repo = UserRepository::default();
+    fn conversation_cache_should_serve_hits_and_never_go_stale() {
+        reset_conv_data();
+        let repo = ConversationRepository::default();
+        settings::update(|s| s.conversation_cache_size = 2);
+        let conv = repo.create("Offer".to_string(), 1).unwrap();
+        assert_eq!(Some(&conv), repo.get(&conv.id).as_ref());
+
+        // a hit does not read the map again
+        CONVERSATION.with_borrow_mut(|m| {
+            m.insert(
+                conv.id,
+                Conversation {
+                    name: "behind the cache".to_string(),
+                    ..conv.clone()
+                },
+            )
+        });
+        assert_eq!("Offer", repo.get(&conv.id).unwrap().name);
+
+        let renamed = repo
+            .update(Conversation {
+                name: "Offer accepted".to_string(),
+                ..conv.clone()
+            })
+            .unwrap();
+        assert_eq!(Some(&renamed), repo.get(&conv.id).as_ref());
+        assert!(repo.set_pinned(conv.id, true).unwrap().pinned);
+        assert!(repo.get(&conv.id).unwrap().pinned);
+        repo.delete(&conv.id).unwrap();
+        assert_eq!(None, repo.get(&conv.id));
+
+        let ids = (0..3)
+            .map(|i| repo.create(format!("Conversation {}", i), 1).unwrap().id)
+            .collect_vec();
+        ids.iter().for_each(|id| {
+            repo.get(id);
+        });
+        let cached =
+            CONVERSATION_CACHE.with_borrow(|c| c.entries.iter().map(|c| c.id).collect_vec());
+        assert_eq!(vec![ids[1], ids[2]], cached);
+    }
+
+    #[test]
+    fn conversation_scans_should_leave_the_cache_alone() {
+        reset_conv_data();
+        let repo = ConversationRepository::default();
+        settings::update(|s| s.conversation_cache_size = 2);
+        let ids = (0..4)
+            .map(|i| repo.create(format!("Conversation {}", i), 1).unwrap().id)
+            .collect_vec();
+        repo.get(&ids[3]);
+        let cached =
+            || CONVERSATION_CACHE.with_borrow(|c| c.entries.iter().map(|c| c.id).collect_vec());
+
+        assert_eq!(
+            Some(ids[0]),
+            repo.find_by_exact_name(1, "Conversation 0").map(|c| c.id)
+        );
+        assert_eq!(Some(ids[3]), repo.most_recent_conversation(1).map(|c| c.id));
+        assert_eq!(vec![ids[3]], cached());
+        assert_eq!(4, repo.archive_inactive(Timestamp::MAX).len());
+        assert!(cached().is_empty());
+
+        repo.get(&ids[0]);
+        repo.find_by_exact_name(1, "missing");
+        assert_eq!(vec![ids[0]], cached());
+    }
+
+    fn find_by_exact_name_should_return_newest_match<S: Storage>(storage: S) {
+        let repo = ConversationRepository::with_storage(&storage);
+        let older = repo.create("Interview prep".to_string(), 1).unwrap();
+        let other = repo.create("Salary".to_string(), 1).unwrap();
+        let newer = repo.create("Interview prep".to_string(), 1).unwrap();
+        repo.create("Salary".to_string(), 2).unwrap();
+
+        assert_eq!(
+            Some(newer.id),
+            repo.find_by_exact_name(1, "Interview prep").map(|c| c.id)
+        );
+        assert_eq!(
+            Some(other.id),
+            repo.find_by_exact_name(1, "Salary").map(|c| c.id)
+        );
+        assert_eq!(None, repo.find_by_exact_name(1, "interview prep"));
+        assert_eq!(None, repo.find_by_exact_name(3, "Salary"));
+
+        repo.update(Conversation {
+            name: "Offer".to_string(),
+            ..newer
+        })
+        .unwrap();
+        assert_eq!(
+            Some(older.id),
+            repo.find_by_exact_name(1, "Interview prep").map(|c| c.id)
+        );
+        assert_eq!(
+            Some(newer.id),
+            repo.find_by_exact_name(1, "Offer").map(|c| c.id)
+        );
+    }
+
+    fn conversation_paged_list_should_skip_empty_when_asked<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(10);
+        let repo = ConversationRepository::with_storage(&storage);
+        let messages = MessageRepository::with_storage(&storage);
+        // conversations 1, 3, 4 and 6 have messages, 2 and 5 are empty
+        for i in 1..=6 {
+            let conv = repo.create(format!("Conversation {}", i), 1).unwrap();
+            if i % 3 != 2 {
+                messages
+                    .insert(Message {
+                        id: 0,
+                        conversation: conv.id,
+                        content: "hi".to_string(),
+                        timestamp: 0,
+                        role: Roles::User,
+                        reply_to: None,
+                    })
+                    .unwrap();
+            }
+        }
+        let ids = |convs: Vec<Conversation>| convs.iter().map(|c| c.id).collect_vec();
+
+        let (_, all) = repo.paged_list(1, None, 0, SortDir::Desc, true);
+        assert_eq!(vec![6, 5, 4, 3, 2, 1], ids(all));
+        let (_, non_empty) = repo.paged_list(1, None, 0, SortDir::Desc, false);
+        assert_eq!(vec![6, 4, 3, 1], ids(non_empty));
+
+        let (cursor, page1) = repo.paged_list(1, None, 2, SortDir::Desc, false);
+        assert_eq!(vec![6, 4], ids(page1));
+        let (cursor, page2) = repo.paged_list(1, cursor, 2, SortDir::Desc, false);
+        assert_eq!(vec![3, 1], ids(page2));
+        let (cursor, page3) = repo.paged_list(1, cursor, 2, SortDir::Desc, false);
+        assert!(page3.is_empty());
+        assert_eq!(None, cursor);
+
+        let (cursor, page1) = repo.paged_list(1, None, 3, SortDir::Asc, false);
+        assert_eq!(vec![1, 3, 4], ids(page1));
+        let (_, page2) = repo.paged_list(1, cursor, 3, SortDir::Asc, false);
+        assert_eq!(vec![6], ids(page2));
+        let (_, page1) = repo.paged_list(1, None, 3, SortDir::Asc, true);
+        assert_eq!(vec![1, 2, 3], ids(page1));
+    }
+
+    #[test]
+    fn legacy_conversation_should_decode_with_created_at() {
+        let legacy = ConversationV0 {
+            id: 1,
+            user: 1,
+            updated_at: 77,
+            name: "old".to_string(),
+        };
+        let decoded = Conversation::from_bytes(std::borrow::Cow::Owned(bitcode::encode(&legacy)));
+        assert_eq!("old", decoded.name);
+        assert_eq!(77, decoded.created_at);
+    }
+
+    fn conversation_paged_list_by_created_should_keep_creation_order<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(1);
+        let repo = ConversationRepository::with_storage(&storage);
+        for i in 1..=3 {
+            repo.insert(Conversation {
+                id: 0,
+                user: 1,
+                updated_at: 0,
+                name: format!("Conversation {}", i),
+                created_at: 0,
+                archived: false,
+                pinned: false,
+                starred: false,
+                deleted_at: None,
+            })
+            .unwrap();
+        }
+        // touching the oldest one moves it up only in the updated order
+        let first = repo.get(&1).unwrap();
+        repo.update(first).unwrap();
+
+        let (_, by_updated) = repo.paged_list(1, None, 0, SortDir::Desc, true);
+        assert_eq!(by_updated.iter().map(|c| c.id).collect_vec(), vec![1, 3, 2]);
+
+        let (cursor, by_created) = repo.paged_list_by_created(1, None, 2);
+        assert_eq!(by_created.iter().map(|c| c.id).collect_vec(), vec![3, 2]);
+        assert_eq!(2, by_created[1].created_at);
+        let (_, rest) = repo.paged_list_by_created(1, cursor, 2);
+        assert_eq!(rest.iter().map(|c| c.id).collect_vec(), vec![1]);
+        assert_eq!(1, repo.get(&1).unwrap().created_at);
+    }
+
+    fn find_values_should_match_find_and_get<S: Storage>(storage: S) {
+        mock_ic0::reset_timestamp_to(1);
+        let messages = MessageRepository::with_storage(&storage);
+        let conversations = ConversationRepository::with_storage(&storage);
+        for i in 1..=4 {
+            conversations
+                .insert(Conversation {
+                    id: 0,
+                    user: i % 2,
+                    updated_at: 0,
+                    created_at: 0,
+                    archived: false,
+                    pinned: false,
+                    starred: false,
+                    deleted_at: None,
+                    name: format!("Conversation {}", i),
+                })
+                .unwrap();
+            messages
+                .insert(Message {
+                    id: 0,
+                    conversation: i % 2,
+                    content: format!("Message {}", i),
+                    timestamp: 0,
+                    role: Roles::User,
+                    reply_to: None,
+                })
+                .unwrap();
+        }
+        let message_ids = messages.conversation_index.find(1, Some(4), 0);
+        assert_eq!(
+            message_ids
+                .iter()
+                .filter_map(|id| messages.get(id))
+                .collect_vec(),
+            messages.conversation_index.find_values(1, Some(4), 0)
+        );
+        let conversation_ids = conversations.user_index.find(0, None, 1);
+        assert_eq!(
+            conversation_ids
+                .iter()
+                .filter_map(|id| conversations.get(id))
+                .collect_vec(),
+            conversations.user_index.find_values(0, None, 1)
+        );
+        let conversation_ids = conversations.created_index.find(1, None, 0);
+        assert_eq!(2, conversation_ids.len());
+        assert_eq!(
+            conversation_ids
+                .iter()
+                .filter_map(|id| conversations.get(id))
+                .collect_vec(),
+            conversations.created_index.find_values(1, None, 0)
+        );
+    }
+
+    fn get_and_insert_user_should_work<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
         let user = User {
             id: 0,
             fullname: "fulan".to_string(),
@@ -1112,10 +6659,8 @@ mod tests {
         assert_eq!("fulan", repo.get(&1).unwrap().fullname);
     }
 
-    #[test]
-    fn update_user_should_work() {
-        reset_user_data();
-        let repo = UserRepository::default();
+    fn update_user_should_work<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
         repo.insert(User {
             id: 0,
             fullname: "fulan".to_string(),
@@ -1135,10 +6680,8 @@ mod tests {
         assert_eq!("fulanah", repo.get(&1).unwrap().fullname);
     }
 
-    #[test]
-    fn delete_user_should_work() {
-        reset_user_data();
-        let repo = UserRepository::default();
+    fn delete_user_should_work<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
         repo.insert(User {
             id: 0,
             fullname: "fulan".to_string(),
@@ -1151,11 +6694,8 @@ mod tests {
         assert!(repo.get(&1).is_none());
     }
 
-    #[test]
-    #[should_panic]
-    fn delete_non_exist_user_should_failed() {
-        reset_user_data();
-        let repo = UserRepository::default();
+    fn delete_non_exist_user_should_failed<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
         repo.insert(User {
             id: 0,
             fullname: "fulan".to_string(),
@@ -1168,10 +6708,8 @@ mod tests {
         repo.delete(&2).unwrap();
     }
 
-    #[test]
-    fn get_user_by_identity_should_work() {
-        reset_user_data();
-        let repo = UserRepository::default();
+    fn get_user_by_identity_should_work<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
 
         let identity = Principal::from_text("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
         repo.insert(User {
@@ -1192,4 +6730,416 @@ mod tests {
         assert!(q.is_some());
         assert_eq!("user1", q.unwrap().fullname);
     }
+
+    fn upsert_user_by_principal_should_insert_then_update<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
+        let identity = Principal::from_text("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
+
+        let created = repo
+            .upsert_by_principal(identity, "fulan".to_string(), "v1".to_string())
+            .unwrap();
+        assert_eq!(1, created.id);
+
+        let updated = repo
+            .upsert_by_principal(identity, "fulanah".to_string(), "v2".to_string())
+            .unwrap();
+        assert_eq!(created.id, updated.id);
+        assert_eq!("fulanah", repo.get(&1).unwrap().fullname);
+        assert_eq!("v2", repo.get_user(identity).unwrap().resume);
+        assert!(repo.get(&2).is_none());
+        assert_eq!(2, repo.peek_next_id());
+    }
+
+    fn user_resume_should_be_redacted_only_when_enabled<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
+        let resume = "Reach me at fulan@mail.com or +62 812 3456 7890".to_string();
+        repo.insert(User {
+            id: 0,
+            fullname: "fulan".to_string(),
+            identity: Principal::anonymous(),
+            resume: resume.clone(),
+        })
+        .unwrap();
+        assert_eq!(resume, repo.get(&1).unwrap().resume);
+
+        settings::update(|s| s.redact_resume = true);
+        let user = repo.get(&1).unwrap();
+        repo.update(user).unwrap();
+        let stored = repo.get(&1).unwrap().resume;
+        assert_eq!("Reach me at [email] or [phone]", stored);
+        assert!(!storage
+            .users()
+            .get(&1)
+            .unwrap()
+            .resume
+            .contains("fulan@mail.com"));
+    }
+
+    fn user_resume_should_be_rejected_over_byte_cap<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
+        settings::update(|s| s.max_resume_bytes = 100);
+        // a 98 bytes text is encoded with a 2 bytes header
+        let user = repo
+            .insert(User {
+                id: 0,
+                fullname: "fulan".to_string(),
+                identity: Principal::anonymous(),
+                resume: "a".repeat(98),
+            })
+            .unwrap();
+
+        let too_large = User {
+            resume: "a".repeat(99),
+            ..user.clone()
+        };
+        assert_eq!(
+            Err(RepositoryError::ResumeTooLarge {
+                bytes: 101,
+                max: 100
+            }),
+            repo.update(too_large.clone())
+        );
+        assert_eq!(Some(user), repo.get(&1));
+        assert!(matches!(
+            repo.insert(too_large),
+            Err(RepositoryError::ResumeTooLarge { .. })
+        ));
+        assert_eq!(2, repo.peek_next_id());
+    }
+
+    fn user_resume_over_a_lowered_cap_should_stay_editable_without_growing<S: Storage>(storage: S) {
+        let repo = UserRepository::with_storage(&storage);
+        let user = repo
+            .insert(User {
+                id: 0,
+                fullname: "fulan".to_string(),
+                identity: Principal::anonymous(),
+                resume: "a".repeat(98),
+            })
+            .unwrap();
+        settings::update(|s| s.max_resume_bytes = 50);
+
+        let edit = |resume: String| {
+            repo.update(User {
+                resume,
+                ..user.clone()
+            })
+        };
+        assert!(edit("b".repeat(98)).is_ok());
+        assert!(edit("c".repeat(60)).is_ok());
+        assert_eq!(
+            Err(RepositoryError::ResumeTooLarge { bytes: 72, max: 50 }),
+            edit("d".repeat(70))
+        );
+        assert!(edit("e".repeat(40)).is_ok());
+        assert_eq!("e".repeat(40), repo.get(&user.id).unwrap().resume);
+    }
+
+    #[test]
+    fn find_relevant_knowledge_should_rank_by_overlap() {
+        KNOWLEDGE.with_borrow_mut(|m| m.clear_new());
+        let repo = KnowledgeRepository::default();
+        [
+            ("How do I negotiate my salary offer?", "salary"),
+            (
+                "How should I prepare a system design interview?",
+                "interview",
+            ),
+            ("What should a resume summary contain?", "resume"),
+        ]
+        .iter()
+        .for_each(|(question, tag)| {
+            repo.insert(QaEntry {
+                id: 0,
+                question: question.to_string(),
+                answer: "answer".to_string(),
+                tags: vec![tag.to_string()],
+            })
+            .unwrap();
+        });
+
+        let found = repo.find_relevant("Tips to negotiate better salary offer", 2);
+        assert_eq!(found.iter().map(|e| e.id).collect_vec(), vec![1]);
+
+        let found = repo.find_relevant("What should a resume contain?", 2);
+        assert_eq!(found.iter().map(|e| e.id).collect_vec(), vec![3, 2]);
+        assert!(repo.find_relevant("cooking pasta", 2).is_empty());
+    }
+
+    #[test]
+    fn export_then_import_all_should_restore_data() {
+        reset_user_data();
+        reset_conv_data();
+        reset_msg_data();
+        let users = UserRepository::default();
+        let convs = ConversationRepository::default();
+        let msgs = MessageRepository::default();
+        let identity = Principal::from_text("2chl6-4hpzw-vqaaa-aaaaa-c").unwrap();
+        users
+            .upsert_by_principal(Principal::anonymous(), "anon".to_string(), String::new())
+            .unwrap();
+        let user = users
+            .upsert_by_principal(identity, "fulan".to_string(), "cv".to_string())
+            .unwrap();
+        convs.create("empty".to_string(), user.id).unwrap();
+        let conv = convs.create("chat".to_string(), user.id).unwrap();
+        let question = msgs
+            .insert(Message {
+                id: 0,
+                conversation: conv.id,
+                content: "question".to_string(),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        let answer = msgs
+            .insert(Message {
+                id: 0,
+                conversation: conv.id,
+                content: "answer".to_string(),
+                timestamp: 0,
+                role: Roles::Assistant,
+                reply_to: Some(question.id),
+            })
+            .unwrap();
+
+        let archive = export_all();
+        assert_eq!(Err(RepositoryError::Conflict), import_all(&archive));
+
+        reset_user_data();
+        reset_conv_data();
+        reset_msg_data();
+        DraftRepository::default().save(conv.id, "left over".to_string());
+        assert_eq!(Err(RepositoryError::Conflict), import_all(&archive));
+
+        clear_all();
+        // ids generated on import differ from the exported ones
+        NEXT_USER_ID.with_borrow_mut(|v| v.set(10).unwrap());
+        NEXT_CONVERSATION_ID.with_borrow_mut(|v| v.set(20).unwrap());
+        NEXT_CHAT_MESSAGE_ID.with_borrow_mut(|v| v.set(30).unwrap());
+        let summary = import_all(&archive).unwrap();
+        assert_eq!(
+            ArchiveSummary {
+                users: 2,
+                conversations: 2,
+                messages: 2
+            },
+            summary
+        );
+
+        let restored_user = users.get_user(identity).unwrap();
+        assert_eq!("cv", restored_user.resume);
+        let (_, restored_convs) = convs.paged_list(restored_user.id, None, 0, SortDir::Desc, true);
+        assert_eq!(
+            restored_convs.iter().map(|c| c.name.as_str()).collect_vec(),
+            vec!["chat", "empty"]
+        );
+        let (_, restored_msgs) = msgs.paged_list(restored_convs[0].id, None, 0);
+        assert_eq!(
+            restored_msgs
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect_vec(),
+            vec!["answer", "question"]
+        );
+        assert_eq!(answer.timestamp, restored_msgs[0].timestamp);
+        assert_eq!(Some(restored_msgs[1].id), restored_msgs[0].reply_to);
+        assert_eq!(1, msgs.replies(restored_msgs[1].id).len());
+    }
+
+    #[test]
+    fn clear_all_should_reset_every_map_and_serial() {
+        let user = UserRepository::default()
+            .upsert_by_principal(Principal::anonymous(), "anon".to_string(), String::new())
+            .unwrap();
+        let conv = ConversationRepository::default()
+            .create("chat".to_string(), user.id)
+            .unwrap();
+        let msgs = MessageRepository::default();
+        let question = msgs
+            .insert(Message {
+                id: 0,
+                conversation: conv.id,
+                content: "question".to_string(),
+                timestamp: 0,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .unwrap();
+        msgs.insert(Message {
+            id: 0,
+            conversation: conv.id,
+            content: "answer".to_string(),
+            timestamp: 0,
+            role: Roles::Assistant,
+            reply_to: Some(question.id),
+        })
+        .unwrap();
+        ReadMarkerRepository::default().mark(user.id, conv.id, question.id);
+        SummaryRepository::default().save(conv.id, "summary".to_string(), question.id);
+        ConversationSettingsRepository::default().save(conv.id, ConversationSettings::default());
+        IdempotencyRepository::default().save(conv.id, "key".to_string(), question.id);
+        TokenUsageRepository::default().add(user.id, 42);
+        ConversationTagRepository::default().add(user.id, conv.id, "jobs");
+        MessageTokenRepository::default().save(question.id, MessageTokens::default());
+        DraftRepository::default().save(conv.id, "draft".to_string());
+        TitleRepository::default().save(conv.id, TitleSource::Manual);
+        LastCreatedRepository::default().record(user.id, conv.created_at);
+        MessageTombstoneRepository::with_storage(&StableStorage).record(user.id, question.id);
+        ConversationTombstoneRepository::with_storage(&StableStorage).record(user.id, conv.id);
+        KnowledgeRepository
+            .insert(QaEntry {
+                id: 0,
+                question: "q".to_string(),
+                answer: "a".to_string(),
+                tags: vec![],
+            })
+            .unwrap();
+
+        clear_all();
+
+        assert!(CHAT_MESSAGE.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_CONVERSATION_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_REPLY_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_TERM_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_TIMESTAMP_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_USER_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_CREATED_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_STALE_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_TRASH_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_READ.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_SUMMARY.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_SETTINGS.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_IDEMPOTENCY.with_borrow(|m| m.is_empty()));
+        assert!(USER.with_borrow(|m| m.is_empty()));
+        assert!(USER_PRINCIPAL_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(USER_TOKEN_USAGE.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_TAG.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_TAG_INDEX.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_TOKENS.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_DRAFT.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_TITLE.with_borrow(|m| m.is_empty()));
+        assert!(CHAT_MESSAGE_TOMBSTONE.with_borrow(|m| m.is_empty()));
+        assert!(CONVERSATION_TOMBSTONE.with_borrow(|m| m.is_empty()));
+        assert!(USER_LAST_CREATED.with_borrow(|m| m.is_empty()));
+        assert!(USER_MESSAGE_COUNT.with_borrow(|m| m.is_empty()));
+        assert!(USER_PINNED_COUNT.with_borrow(|m| m.is_empty()));
+        assert!(is_storage_empty());
+        assert!(KNOWLEDGE.with_borrow(|m| m.is_empty()));
+        assert_eq!(1, MessageRepository::default().peek_next_id());
+        assert_eq!(1, ConversationRepository::default().peek_next_id());
+        assert_eq!(1, UserRepository::default().peek_next_id());
+        assert_eq!(1, KnowledgeRepository.peek_next_id());
+    }
+
+    fn conversation_tags_should_stay_in_sync_with_their_index<S: Storage>(storage: S) {
+        let conversations = ConversationRepository::with_storage(&storage);
+        let first = conversations.create("first".to_string(), 1).unwrap().id;
+        let second = conversations.create("second".to_string(), 1).unwrap().id;
+        let theirs = conversations.create("theirs".to_string(), 2).unwrap().id;
+        let tags = ConversationTagRepository::with_storage(&storage);
+        assert!(tags.add(1, first, "jobs"));
+        assert!(!tags.add(1, first, "jobs"));
+        assert!(tags.add(1, first, "career"));
+        assert!(tags.add(1, second, "jobs"));
+        assert!(tags.add(2, theirs, "jobs"));
+        assert_eq!(vec!["career", "jobs"], tags.tags(first));
+        assert_eq!(vec![first, second], tags.tagged(1, "jobs"));
+        assert_eq!(
+            vec![("career".to_string(), 1), ("jobs".to_string(), 2)],
+            tags.list_tags(1)
+        );
+
+        assert!(tags.remove(1, first, "jobs"));
+        assert!(!tags.remove(1, first, "jobs"));
+        assert_eq!(vec![second], tags.tagged(1, "jobs"));
+        tags.remove_all(1, first);
+        assert!(tags.tags(first).is_empty());
+        assert!(tags.tagged(1, "career").is_empty());
+        assert_eq!(vec![theirs], tags.tagged(2, "jobs"));
+        assert_eq!(
+            storage.conversation_tags().len(),
+            storage.conversation_tag_index().len()
+        );
+    }
+
+    fn quarantine_orphans_should_move_messages_of_missing_conversations<S: Storage>(storage: S) {
+        let conversations = ConversationRepository::with_storage(&storage);
+        let messages = MessageRepository::with_storage(&storage);
+        let kept = conversations.create("kept".to_string(), 1).unwrap();
+        let gone = conversations.create("gone".to_string(), 1).unwrap();
+        let insert = |conversation, content: &str| {
+            messages
+                .insert(Message::builder(conversation).content(content).build())
+                .unwrap()
+        };
+        let handled = |ids: Vec<MessageId>| {
+            Ok(OrphanReport {
+                handled: ids,
+                failed: vec![],
+            })
+        };
+        insert(kept.id, "still here");
+        let question = insert(gone.id, "salary question");
+        let orphan = messages
+            .insert(
+                Message::builder(gone.id)
+                    .content("salary answer")
+                    .role(Roles::Assistant)
+                    .reply_to(question.id)
+                    .build(),
+            )
+            .unwrap();
+        conversations.delete(&gone.id).unwrap();
+
+        assert_eq!(
+            handled(vec![question.id, orphan.id]),
+            quarantine_orphans(&storage)
+        );
+        let quarantine = conversations
+            .find_by_exact_name(NEW_ENTITY_ID, QUARANTINE_CONVERSATION_NAME)
+            .unwrap();
+        let moved = messages.get(&orphan.id).unwrap();
+        assert_eq!((quarantine.id, None), (moved.conversation, moved.reply_to));
+        assert!(messages.replies(question.id).is_empty());
+        assert!(messages
+            .conversation_index
+            .find(gone.id, None, 0)
+            .is_empty());
+        assert_eq!(
+            vec![orphan.id, question.id],
+            messages.conversation_index.find(quarantine.id, None, 0)
+        );
+        assert_eq!(
+            vec![orphan.id, question.id],
+            messages
+                .search_messages(quarantine.id, "salary", 0, SearchOrder::Newest)
+                .iter()
+                .map(|m| m.id)
+                .collect_vec()
+        );
+        assert_eq!(handled(vec![]), quarantine_orphans(&storage));
+
+        let orphan = insert(gone.id, "another one");
+        settings::update(|s| s.delete_orphans = true);
+        assert_eq!(handled(vec![orphan.id]), quarantine_orphans(&storage));
+        assert_eq!(None, messages.get(&orphan.id));
+        assert!(messages
+            .conversation_index
+            .find(gone.id, None, 0)
+            .is_empty());
+    }
+
+    #[test]
+    fn import_all_should_reject_malformed_archive() {
+        reset_user_data();
+        reset_conv_data();
+        reset_msg_data();
+        assert!(matches!(
+            import_all(b"not an archive"),
+            Err(RepositoryError::InvalidData { .. })
+        ));
+    }
 }
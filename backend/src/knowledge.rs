@@ -1,4 +1,35 @@
-const SYSTEM: &'static str = "
+use std::{fmt::Debug, future::Future};
+
+use ic_llm::{ChatMessage, Model, Role};
+use itertools::Itertools;
+
+use crate::{
+    entities::{Message, Persona, Roles, KNOWLEDGE_REPOSITORY},
+    service::errors::LlmError,
+    settings,
+    utils::{count_tokens_streaming, truncate_chars, truncate_tokens},
+};
+
+/// Model served by the LLM canister.
+pub const DEFAULT_MODEL: &str = "llama3.1:8b";
+
+/// Context window used for models without a known limit.
+pub const DEFAULT_CONTEXT_LIMIT: usize = 4_096;
+
+/// Reply given to messages too short to be worth a model call.
+pub const CLARIFICATION_REPLY: &str =
+    "Could you tell me a bit more about what you need help with? For example, a resume review, interview preparation, or salary negotiation.";
+
+/// Starts the replies given in place of the LLM when the `stub_llm` setting is on.
+pub const STUB_REPLY_PREFIX: &str = "[stub] ";
+
+/// Appended to user messages cut down to the token cap of the settings.
+pub const TRUNCATION_MARKER: &str = "\n\n[message truncated]";
+
+/// Maximum amount of knowledge base examples appended to the system prompt.
+const KNOWLEDGE_EXAMPLES_LIMIT: usize = 3;
+
+const SYSTEM: &str = "
 You are an AI Career Coach specializing in helping tech professionals advance in their careers.
 Your name is **ICV**.
 You provide expert guidance on job applications, resume optimization, technical interviews, salary negotiation, and career transitions.
@@ -24,3 +55,474 @@ You are friendly, supportive, and knowledgeable. Your tone is casual and caring,
 - If a user asks for unrealistic outcomes (e.g., \"How do I become a Google engineer in 1 month?\"), provide realistic, achievable steps.
 - If you don't know the answer, or it is unrelated to your expertise (e.g., cooking advice), simply state that it is outside your scope.
 ";
+
+const RESUME_REVIEWER: &str = "
+You are **ICV**, a resume reviewer for tech professionals.
+Review the resumes and LinkedIn profiles shared with you: point out what an ATS or a recruiter would trip on, and rewrite weak bullet points into measurable achievements.
+Keep the feedback specific, ordered by impact, and encouraging.
+Do NOT invent experience the user did not mention.
+";
+
+const INTERVIEW_COACH: &str = "
+You are **ICV**, an interview coach for tech professionals.
+Run mock interviews when asked: coding problems, system design and STAR-based behavioral questions, one question at a time.
+After each answer, give short feedback on what went well and what to improve, then move on.
+Keep a supportive tone, interviews are stressful.
+";
+
+const SUMMARY: &str = "
+Summarize the following conversation between a user and **ICV**, their career coach.
+Keep the facts the user shared about themselves, their goals, and the advice already given.
+Answer with the summary only, in a few short bullet points.
+";
+
+const TITLE: &str = "
+Give a short title to the following conversation between a user and **ICV**, their career coach.
+Answer with the title only, in a few words, without quotes.
+";
+
+/// Longest title kept from the LLM reply, in characters.
+pub const MAX_TITLE_CHARS: usize = 60;
+
+impl Persona {
+    /// System prompt the persona is built on.
+    pub fn system_prompt(&self) -> &'static str {
+        match self {
+            Persona::CareerCoach => SYSTEM,
+            Persona::ResumeReviewer => RESUME_REVIEWER,
+            Persona::InterviewCoach => INTERVIEW_COACH,
+        }
+    }
+}
+
+/// Maps a model name onto the models served by the LLM canister, `None` when it serves no such
+/// model.
+pub fn served_model(name: &str) -> Option<Model> {
+    match name {
+        "llama3.1:8b" => Some(Model::Llama3_1_8B),
+        "qwen3:32b" => Some(Model::Qwen3_32B),
+        "llama4-scout" => Some(Model::Llama4Scout),
+        _ => None,
+    }
+}
+
+/// Returns the context window size, in tokens, of the given model.
+/// Unknown models fall back to [`DEFAULT_CONTEXT_LIMIT`].
+pub fn model_context_limit(model: &str) -> usize {
+    match model {
+        "llama3.1:8b" => 8_192,
+        "qwen3:32b" => 32_768,
+        "llama4-scout" => 131_072,
+        _ => DEFAULT_CONTEXT_LIMIT,
+    }
+}
+
+/// Limits of a chat completion.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChatParams {
+    /// Most tokens kept from the reply.
+    pub max_tokens: u32,
+}
+
+/// Chat completion backend used by the services.
+pub trait LlmClient {
+    /// Sends the assembled messages to the model and returns the assistant reply.
+    fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        params: ChatParams,
+    ) -> impl Future<Output = Result<String, LlmError>>;
+}
+
+/// [`LlmClient`] calling the LLM canister.
+#[derive(Debug, Default, Clone)]
+pub struct IcLlm;
+
+impl LlmClient for IcLlm {
+    /// The LLM canister takes no token limit, the reply is cut to `params.max_tokens` once
+    /// received, see [`checked_reply`]. A model it does not serve fails without calling it,
+    /// and a blank reply fails as retryable. A call the LLM canister rejects traps inside
+    /// `ic_llm`, which rolls the whole turn back instead of returning an error.
+    async fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ChatMessage>,
+        params: ChatParams,
+    ) -> Result<String, LlmError> {
+        let served = served_model(model).ok_or_else(|| LlmError::CallFailed {
+            reason: format!("model {} is not served", model),
+            retryable: false,
+        })?;
+        checked_reply(ic_llm::chat(served, messages).await, params)
+    }
+}
+
+/// Fails a blank reply of the LLM canister as retryable, the same call usually answers, and
+/// cuts a longer reply down to `params.max_tokens` tokens.
+fn checked_reply(reply: String, params: ChatParams) -> Result<String, LlmError> {
+    if reply.trim().is_empty() {
+        return Err(LlmError::CallFailed {
+            reason: "the LLM replied with nothing".to_string(),
+            retryable: true,
+        });
+    }
+    let cut = truncate_tokens(&reply, params.max_tokens as usize).map(str::to_string);
+    Ok(cut.unwrap_or(reply))
+}
+
+/// Step applied on the assistant reply before it is stored.
+pub trait ResponsePostProcessor: Debug {
+    fn process(&self, text: String) -> String;
+}
+
+/// Cuts replies longer than `max_chars` characters.
+#[derive(Debug, Clone)]
+pub struct MaxLengthProcessor {
+    pub max_chars: usize,
+}
+
+impl ResponsePostProcessor for MaxLengthProcessor {
+    fn process(&self, text: String) -> String {
+        if text.chars().count() <= self.max_chars {
+            return text;
+        }
+        truncate_chars(&text, self.max_chars)
+    }
+}
+
+/// Appends a disclaimer paragraph to every reply.
+#[derive(Debug, Clone)]
+pub struct DisclaimerProcessor {
+    pub disclaimer: String,
+}
+
+impl ResponsePostProcessor for DisclaimerProcessor {
+    fn process(&self, text: String) -> String {
+        format!("{}\n\n{}", text, self.disclaimer)
+    }
+}
+
+/// Builds the system prompt from `base`, or the default prompt when `None`, augmented with
+/// knowledge base examples relevant to `query`.
+pub fn system_prompt(base: Option<&str>, query: Option<&str>) -> String {
+    let base = base.unwrap_or(SYSTEM);
+    let examples = query
+        .map(|q| KNOWLEDGE_REPOSITORY.find_relevant(q, KNOWLEDGE_EXAMPLES_LIMIT))
+        .unwrap_or_default();
+    if examples.is_empty() {
+        return base.to_string();
+    }
+    let examples = examples
+        .iter()
+        .map(|e| format!("Q: {}\nA: {}", e.question, e.answer))
+        .join("\n\n");
+    format!("{}\n## Examples:\n{}\n", base, examples)
+}
+
+/// Assembles the messages sent to the LLM: the system prompt followed by the most recent
+/// messages of `history` that fit in the model context window.
+///
+/// `history` is expected newest first, as returned by `MessageRepository::paged_list`.
+/// The completion reserve from the settings is kept out of the budget, and the latest user
+/// message is used to retrieve examples from the knowledge base. `prompt` overrides the
+/// default system prompt.
+pub fn build_chat_context(
+    model: &str,
+    prompt: Option<&str>,
+    history: &[Message],
+) -> Vec<ChatMessage> {
+    let query = history.iter().find(|m| m.role == Roles::User);
+    let system = system_prompt(prompt, query.map(|m| m.content.as_str()));
+    let reserve = settings::get().completion_reserve as usize;
+    let mut budget = model_context_limit(model)
+        .saturating_sub(reserve)
+        .saturating_sub(count_tokens_streaming(&system));
+
+    let mut context = history
+        .iter()
+        .take_while(|m| {
+            let tokens = count_tokens_streaming(&m.content);
+            if tokens > budget {
+                return false;
+            }
+            budget -= tokens;
+            true
+        })
+        .map(Message::to_ic_message)
+        .collect_vec();
+    context.push(ChatMessage {
+        role: Role::System,
+        content: system,
+    });
+    context.reverse();
+    context
+}
+
+/// Renders messages in the given order as `Role: content` turns separated by a blank line.
+pub fn transcript<'a>(messages: impl IntoIterator<Item = &'a Message>) -> String {
+    messages
+        .into_iter()
+        .map(|m| format!("{:?}: {}", m.role, m.content))
+        .join("\n\n")
+}
+
+/// Deterministic reply standing in for the LLM, echoing the last message of the prompt.
+pub fn stub_reply(messages: &[ChatMessage]) -> String {
+    let prompt = messages.last().map_or("", |m| m.content.as_str());
+    format!("{}{}", STUB_REPLY_PREFIX, prompt)
+}
+
+/// Assembles the messages asking the LLM to summarize `messages`, expected newest first.
+/// A previous summary among them is folded into the new one.
+pub fn summary_request(messages: &[Message]) -> Vec<ChatMessage> {
+    let transcript = transcript(messages.iter().rev());
+    vec![
+        ChatMessage {
+            role: Role::System,
+            content: SUMMARY.to_string(),
+        },
+        ChatMessage {
+            role: Role::User,
+            content: transcript,
+        },
+    ]
+}
+
+/// Assembles the messages asking the LLM to title a conversation from `messages`, expected
+/// newest first.
+pub fn title_request(messages: &[Message]) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage {
+            role: Role::System,
+            content: TITLE.to_string(),
+        },
+        ChatMessage {
+            role: Role::User,
+            content: transcript(messages.iter().rev()),
+        },
+    ]
+}
+
+/// Extracts a title from the LLM reply to a [`title_request`]: its first non-blank line
+/// without surrounding quotes or emphasis, cut down to [`MAX_TITLE_CHARS`].
+pub fn title_from_reply(reply: &str) -> Option<String> {
+    let line = reply.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let title =
+        line.trim_matches(|c: char| matches!(c, '"' | '\'' | '*' | '#') || c.is_whitespace());
+    (!title.is_empty()).then(|| truncate_chars(title, MAX_TITLE_CHARS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::mock_ic0, QaEntry, Repository};
+
+    /// Builds `n` messages of roughly 500 tokens each, newest first.
+    fn long_history(n: u64) -> Vec<Message> {
+        (1..=n)
+            .rev()
+            .map(|i| Message {
+                id: i,
+                conversation: 1,
+                content: format!("{} {}", i, "career ".repeat(500)),
+                timestamp: i,
+                role: Roles::User,
+                reply_to: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn post_processors_should_apply_in_order() {
+        let cap = MaxLengthProcessor { max_chars: 5 };
+        let disclaimer = DisclaimerProcessor {
+            disclaimer: "Not legal advice.".to_string(),
+        };
+        let chain: [&dyn ResponsePostProcessor; 2] = [&cap, &disclaimer];
+        let text = chain
+            .iter()
+            .fold("Negotiate firmly".to_string(), |t, p| p.process(t));
+        assert_eq!("Negot…\n\nNot legal advice.", text);
+
+        let chain: [&dyn ResponsePostProcessor; 2] = [&disclaimer, &cap];
+        let text = chain
+            .iter()
+            .fold("Negotiate firmly".to_string(), |t, p| p.process(t));
+        assert_eq!("Negot…", text);
+        assert_eq!("short", cap.process("short".to_string()));
+    }
+
+    #[test]
+    fn unknown_model_should_fallback_to_default_limit() {
+        assert_eq!(DEFAULT_CONTEXT_LIMIT, model_context_limit("gpt-unknown"));
+        assert_eq!(8_192, model_context_limit("llama3.1:8b"));
+    }
+
+    #[test]
+    fn ic_llm_should_fail_unserved_models_and_blank_replies() {
+        let params = ChatParams { max_tokens: 1 };
+        assert_eq!(
+            Err(LlmError::CallFailed {
+                reason: "model gpt-unknown is not served".to_string(),
+                retryable: false
+            }),
+            mock_ic0::block_on(IcLlm.chat("gpt-unknown", vec![], params))
+        );
+        assert_eq!(
+            Err(LlmError::CallFailed {
+                reason: "the LLM replied with nothing".to_string(),
+                retryable: true
+            }),
+            checked_reply(" \n".to_string(), params)
+        );
+        assert_eq!(
+            Ok("Sure".to_string()),
+            checked_reply("Sure thing".to_string(), params)
+        );
+        assert_eq!(
+            Ok("Sure thing".to_string()),
+            checked_reply("Sure thing".to_string(), ChatParams { max_tokens: 2 })
+        );
+    }
+
+    #[test]
+    fn served_model_should_map_the_models_with_a_known_limit() {
+        assert!(matches!(
+            served_model(DEFAULT_MODEL),
+            Some(Model::Llama3_1_8B)
+        ));
+        assert!(matches!(served_model("qwen3:32b"), Some(Model::Qwen3_32B)));
+        assert!(matches!(
+            served_model("llama4-scout"),
+            Some(Model::Llama4Scout)
+        ));
+        assert!(served_model("gpt-unknown").is_none());
+    }
+
+    #[test]
+    fn context_should_start_with_system_and_end_with_newest() {
+        let history = long_history(3);
+        let context = build_chat_context("llama3.1:8b", None, &history);
+        assert_eq!(4, context.len());
+        assert!(matches!(context[0].role, Role::System));
+        assert_eq!(history[0].content, context[3].content);
+    }
+
+    #[test]
+    fn context_should_be_trimmed_by_model_window() {
+        let history = long_history(40);
+        let default = build_chat_context("gpt-unknown", None, &history).len();
+        let llama = build_chat_context("llama3.1:8b", None, &history).len();
+        let qwen = build_chat_context("qwen3:32b", None, &history).len();
+        assert!(default < llama);
+        assert!(llama < qwen);
+        assert_eq!(history.len() + 1, qwen);
+    }
+
+    #[test]
+    fn completion_reserve_should_shrink_the_context() {
+        let history = long_history(40);
+        let before = build_chat_context("llama3.1:8b", None, &history).len();
+        settings::update(|s| s.completion_reserve = 4_096);
+        let after = build_chat_context("llama3.1:8b", None, &history).len();
+        assert!(after < before);
+    }
+
+    #[test]
+    fn transcript_should_separate_labelled_turns_with_blank_lines() {
+        let turns = [
+            (Roles::System, "Be concise."),
+            (Roles::User, "Hi,\nI was laid off"),
+            (Roles::Assistant, "Sorry to hear that."),
+        ]
+        .into_iter()
+        .map(|(role, content)| Message::builder(1).content(content).role(role).build())
+        .collect_vec();
+
+        assert_eq!(
+            "System: Be concise.\n\nUser: Hi,\nI was laid off\n\nAssistant: Sorry to hear that.",
+            transcript(&turns)
+        );
+        assert_eq!("", transcript(&[]));
+    }
+
+    #[test]
+    fn summary_request_should_hold_oldest_first_transcript() {
+        let mut history = long_history(2);
+        history[0].content = "Thanks!".to_string();
+        history[0].role = Roles::Assistant;
+        history[1].content = "Hi, I was laid off".to_string();
+
+        let request = summary_request(&history);
+        assert_eq!(2, request.len());
+        assert_eq!(SUMMARY, request[0].content);
+        assert_eq!(
+            "User: Hi, I was laid off\n\nAssistant: Thanks!",
+            request[1].content
+        );
+    }
+
+    #[test]
+    fn title_from_reply_should_keep_the_first_line_unquoted() {
+        assert_eq!(
+            Some("Salary negotiation".to_string()),
+            title_from_reply("\n  \"Salary negotiation\"\nHope it helps!")
+        );
+        assert_eq!(
+            Some("Interview prep".to_string()),
+            title_from_reply("**Interview prep**")
+        );
+        assert_eq!(None, title_from_reply(" \n \"\" "));
+        let long = title_from_reply(&"career ".repeat(20)).unwrap();
+        assert_eq!(MAX_TITLE_CHARS + 1, long.chars().count());
+    }
+
+    #[test]
+    fn context_should_include_relevant_knowledge() {
+        KNOWLEDGE_REPOSITORY
+            .insert(QaEntry {
+                id: 0,
+                question: "How do I negotiate my salary?".to_string(),
+                answer: "Research the market range first.".to_string(),
+                tags: vec!["salary".to_string()],
+            })
+            .unwrap();
+        let mut history = long_history(1);
+        history[0].content = "Any salary negotiation tips?".to_string();
+
+        let context = build_chat_context("llama3.1:8b", None, &history);
+        assert!(context[0]
+            .content
+            .contains("Q: How do I negotiate my salary?\nA: Research the market range first."));
+
+        history[0].content = "Cover letter structure?".to_string();
+        let context = build_chat_context("llama3.1:8b", None, &history);
+        assert_eq!(SYSTEM, context[0].content);
+    }
+
+    #[test]
+    fn personas_should_assemble_different_system_prompts() {
+        let history = long_history(1);
+        let system = |persona: Persona| {
+            build_chat_context("llama3.1:8b", Some(persona.system_prompt()), &history)[0]
+                .content
+                .clone()
+        };
+        assert_eq!(SYSTEM, system(Persona::default()));
+        assert_eq!(RESUME_REVIEWER, system(Persona::ResumeReviewer));
+        assert_eq!(INTERVIEW_COACH, system(Persona::InterviewCoach));
+        assert_ne!(
+            system(Persona::ResumeReviewer),
+            system(Persona::InterviewCoach)
+        );
+    }
+
+    #[test]
+    fn prompt_override_should_replace_default_system_prompt() {
+        let history = long_history(1);
+        let context = build_chat_context("llama3.1:8b", Some("You review resumes."), &history);
+        assert_eq!("You review resumes.", context[0].content);
+        assert_eq!(2, context.len());
+    }
+}
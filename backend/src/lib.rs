@@ -4,6 +4,7 @@ pub use entities::*;
 pub mod knowledge;
 pub mod service;
 pub use service::*;
+pub mod settings;
 pub mod utils;
 pub use utils::*;
 
@@ -0,0 +1,137 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::knowledge::DEFAULT_MODEL;
+
+/// Deployment wide settings consulted by the services. They are kept on the heap, an upgrade
+/// sets them back to their defaults.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Settings {
+    /// Model used for the chat completions.
+    pub model: String,
+    /// Tokens kept free in the model context window for the expected completion.
+    pub completion_reserve: u64,
+    /// Masks emails and phone numbers of resumes before they are stored.
+    pub redact_resume: bool,
+    /// Conversations a user may pin at once. Zero means no cap.
+    pub max_pinned: u64,
+    /// Largest resume stored, in bytes once encoded. Zero means no cap.
+    pub max_resume_bytes: u64,
+    /// Messages with fewer tokens are answered with a clarification request, without the LLM.
+    pub min_prompt_tokens: u64,
+    /// User messages with more tokens are truncated, or rejected with `reject_long_messages`.
+    /// Zero means no cap.
+    pub max_message_tokens: u64,
+    /// Rejects the user messages over `max_message_tokens` instead of truncating them.
+    pub reject_long_messages: bool,
+    /// Messages left without a conversation are deleted instead of quarantined.
+    pub delete_orphans: bool,
+    /// Allows admins to wipe the whole storage, meant for tests and local deployments only.
+    pub allow_clear_all: bool,
+    /// Sending to an archived conversation unarchives it instead of being rejected.
+    pub unarchive_on_send: bool,
+    /// Messages the canister stores at most, inserts beyond it are rejected. Zero means no cap.
+    pub max_messages: u64,
+    /// Rejects conversation names holding one of the `blocked_words`.
+    pub filter_conversation_names: bool,
+    /// Lowercase words not allowed in filtered content.
+    pub blocked_words: Vec<String>,
+    /// Recently fetched conversations kept deserialized in memory. Zero disables the cache.
+    pub conversation_cache_size: u64,
+    /// Largest page of messages returned at once.
+    pub max_page_size: u64,
+    /// Most tokens kept from each completion, unless overridden on the conversation.
+    pub max_tokens: u32,
+    /// Latest messages of a conversation searched for the same user message before storing a
+    /// new one, the existing message is reused instead. Zero disables the detection.
+    pub dedup_window: u64,
+    /// Records the prompt and completion tokens of each message written by the LLM.
+    pub record_message_tokens: bool,
+    /// Replies with a stub echoing the prompt instead of calling the LLM, meant for local
+    /// deployments without the LLM canister.
+    pub stub_llm: bool,
+    /// Milliseconds an assistant reply has to age before it can be regenerated. Zero disables
+    /// the limit.
+    pub min_regenerate_interval: u64,
+    /// Messages a conversation holds before its name is generated again by the LLM, once,
+    /// unless its user renamed it. Zero disables the generated titles.
+    pub auto_title_after: u64,
+    /// Milliseconds a user waits between two conversation creations. Zero disables the limit.
+    pub conversation_create_cooldown: u64,
+    /// Messages the conversations of a user hold at most, messages sent beyond it are rejected.
+    /// Zero means no cap.
+    pub max_user_messages: u64,
+    /// Milliseconds a trashed conversation is kept before admins can purge it. Zero keeps the
+    /// trash forever.
+    pub trash_retention: u64,
+    /// Largest draft saved, in bytes. Zero means no cap.
+    pub max_draft_bytes: u64,
+    /// Milliseconds the deletes are remembered for delta syncs. Older tombstones can be pruned
+    /// by admins, and syncs from further back are rejected. Zero keeps them forever.
+    pub sync_horizon: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            model: DEFAULT_MODEL.to_string(),
+            completion_reserve: 512,
+            redact_resume: false,
+            max_pinned: 5,
+            max_resume_bytes: 64 * 1024,
+            min_prompt_tokens: 0,
+            max_message_tokens: 2_048,
+            reject_long_messages: false,
+            delete_orphans: false,
+            allow_clear_all: false,
+            unarchive_on_send: false,
+            max_messages: 0,
+            filter_conversation_names: false,
+            blocked_words: Vec::new(),
+            conversation_cache_size: 32,
+            max_page_size: 100,
+            max_tokens: 1_024,
+            dedup_window: 0,
+            record_message_tokens: false,
+            stub_llm: false,
+            min_regenerate_interval: 0,
+            auto_title_after: 0,
+            conversation_create_cooldown: 0,
+            max_user_messages: 0,
+            trash_retention: 0,
+            max_draft_bytes: 16 * 1024,
+            sync_horizon: 30 * 24 * 60 * 60 * 1_000,
+        }
+    }
+}
+
+thread_local! {
+    static SETTINGS: RefCell<Settings> = RefCell::new(Settings::default());
+}
+
+/// Returns a snapshot of the current settings.
+pub fn get() -> Settings {
+    SETTINGS.with_borrow(|s| s.clone())
+}
+
+/// Applies `f` on the current settings.
+pub fn update<F>(f: F)
+where
+    F: FnOnce(&mut Settings),
+{
+    SETTINGS.with_borrow_mut(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_settings_should_be_visible_on_get() {
+        assert_eq!(get(), Settings::default());
+        update(|s| s.completion_reserve = 1024);
+        assert_eq!(1024, get().completion_reserve);
+    }
+}
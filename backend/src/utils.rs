@@ -1,34 +1,224 @@
 use anyhow::Result;
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::cl100k_base_singleton;
 
 /// Nanoseconds at 1 millisecond
 pub const NANOS_IN_MILLIS: u64 = 1_000_000;
 
+/// Minimum amount of digits for a run of characters to be considered a phone number
+const MIN_PHONE_DIGITS: usize = 9;
+/// Maximum amount of digit groups in a phone number, longer runs are lists of numbers
+const MAX_PHONE_GROUPS: usize = 5;
+
+/// Markers wrapped around the matched term of a snippet.
+pub const HIGHLIGHT_OPEN: &str = "<mark>";
+pub const HIGHLIGHT_CLOSE: &str = "</mark>";
+
+/// Check deciding whether a user provided text may be stored.
+pub trait ContentFilter: std::fmt::Debug {
+    fn allows(&self, text: &str) -> bool;
+}
+
+/// Rejects texts holding one of the blocked words, compared as lowercase terms.
+#[derive(Debug, Clone)]
+pub struct BlocklistFilter {
+    pub words: Vec<String>,
+}
+
+impl ContentFilter for BlocklistFilter {
+    fn allows(&self, text: &str) -> bool {
+        !terms(text).any(|t| self.words.contains(&t))
+    }
+}
+
 /// Tokenize string from given string, using bpe cl100k.
+/// The encoder is built once and shared across calls.
 fn bpe_tokenize(text: &str) -> Result<Vec<String>> {
-    let bpe = cl100k_base()?;
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
     bpe.split_by_token(text, true)
 }
 
 /// Count token size from given string, using bpe cl100k.
-fn token_count(text: &str) -> Result<usize> {
+pub fn token_count(text: &str) -> Result<usize> {
     bpe_tokenize(text).map(|t| t.len())
 }
 
+/// Count token size from given string, using bpe cl100k.
+/// Unlike [`token_count`], the token pieces are never decoded into strings.
+pub fn count_tokens_streaming(text: &str) -> usize {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Cuts a text down to its first `max_tokens` tokens, using bpe cl100k, or `None` when it
+/// already fits. A character split across tokens is dropped whole.
+pub fn truncate_tokens(text: &str, max_tokens: usize) -> Option<&str> {
+    let bpe = cl100k_base_singleton();
+    let bpe = bpe.lock();
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return None;
+    }
+    let mut end: usize = bpe
+        ._decode_native_and_split(tokens[..max_tokens].to_vec())
+        .map(|t| t.len())
+        .sum();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(&text[..end])
+}
+
+/// Cuts a text down to `max_chars` characters, marking the cut with an ellipsis.
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    let mut chars = text.chars();
+    let mut truncated: String = chars.by_ref().take(max_chars).collect();
+    if chars.next().is_some() {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// Splits a text into lowercase alphanumeric terms, used for lookups by keyword.
+pub fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Cuts a snippet around the first word of `text` matching one of the lowercase `terms`, with
+/// up to `radius` characters on each side and the word wrapped in the highlight markers.
+/// Returns the snippet along with the character offset of the word in `text`.
+pub fn highlight(text: &str, terms: &[String], radius: usize) -> Option<(String, usize)> {
+    let (start, word) = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| (w.as_ptr() as usize - text.as_ptr() as usize, w))
+        .find(|(_, w)| terms.contains(&w.to_lowercase()))?;
+    let end = start + word.len();
+    let from = text[..start]
+        .char_indices()
+        .rev()
+        .take(radius)
+        .last()
+        .map_or(start, |(i, _)| i);
+    let to = text[end..]
+        .char_indices()
+        .nth(radius)
+        .map_or(text.len(), |(i, _)| end + i);
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[from..start]);
+    snippet.push_str(HIGHLIGHT_OPEN);
+    snippet.push_str(word);
+    snippet.push_str(HIGHLIGHT_CLOSE);
+    snippet.push_str(&text[end..to]);
+    if to < text.len() {
+        snippet.push('…');
+    }
+    Some((snippet, text[..start].chars().count()))
+}
+
+/// Masks email addresses and phone numbers found in a resume.
+pub fn redact_resume(resume: &str) -> String {
+    mask_phone_numbers(resume)
+        .split_inclusive(char::is_whitespace)
+        .map(mask_email)
+        .collect()
+}
+
+/// Replaces `word` with a placeholder when it looks like `local@domain.tld`,
+/// keeping the surrounding punctuation and whitespace.
+fn mask_email(word: &str) -> String {
+    let core = word.trim_matches(|c: char| !(c.is_alphanumeric() || c == '@'));
+    let is_email = core.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty()
+            && domain
+                .split_once('.')
+                .is_some_and(|(host, tld)| !host.is_empty() && !tld.is_empty())
+    });
+    if is_email {
+        word.replacen(core, "[email]", 1)
+    } else {
+        word.to_string()
+    }
+}
+
+/// Replaces runs of digits, spaces, `+-().` holding at least [`MIN_PHONE_DIGITS`] digits,
+/// see [`is_phone_shaped`].
+fn mask_phone_numbers(text: &str) -> String {
+    let is_phone_byte = |b: u8| b.is_ascii_digit() || b" +-().".contains(&b);
+    let bytes = text.as_bytes();
+    let mut masked = String::with_capacity(text.len());
+    let (mut copied, mut i) = (0, 0);
+    while i < bytes.len() {
+        let starts = (bytes[i].is_ascii_digit() || bytes[i] == b'+' || bytes[i] == b'(')
+            && (i == 0 || !bytes[i - 1].is_ascii_alphanumeric());
+        if !starts {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < bytes.len() && is_phone_byte(bytes[end]) {
+            end += 1;
+        }
+        while end > i && !bytes[end - 1].is_ascii_digit() {
+            end -= 1;
+        }
+        if is_phone_shaped(&text[i..end]) {
+            masked.push_str(&text[copied..i]);
+            masked.push_str("[phone]");
+            copied = end;
+        }
+        i = end.max(i + 1);
+    }
+    masked.push_str(&text[copied..]);
+    masked
+}
+
+/// Tells whether a run of digits and separators is grouped like a phone number: at least
+/// [`MIN_PHONE_DIGITS`] digits, in at most [`MAX_PHONE_GROUPS`] groups which are not all years,
+/// so that ranges like `2019-2023 2020-2024` are kept.
+fn is_phone_shaped(run: &str) -> bool {
+    let groups = run
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|g| !g.is_empty())
+        .collect::<Vec<_>>();
+    let digits: usize = groups.iter().map(|g| g.len()).sum();
+    let is_year = |g: &&str| g.len() == 4 && (g.starts_with("19") || g.starts_with("20"));
+    digits >= MIN_PHONE_DIGITS
+        && (groups.len() == 1 || (groups.len() <= MAX_PHONE_GROUPS && !groups.iter().all(is_year)))
+}
+
 /// Gets current timestamp inside a canister, in milliseconds since the epoch (1970-01-01)
 pub fn timestamp() -> u64 {
     ic_cdk::api::time() / NANOS_IN_MILLIS
 }
 
+/// Writes a message into the canister debug log.
+pub fn log(message: &str) {
+    ic_cdk::api::print(message)
+}
+
 #[cfg(test)]
 pub mod mock_ic0 {
-    use std::cell::{Cell, RefCell};
+    use std::{
+        cell::{Cell, RefCell},
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
 
     use candid::Principal;
 
     thread_local! {
         static TIMESTAMP: Cell<u64> = Cell::new(0);
         static CALLER: RefCell<String> = RefCell::new("2chl6-4hpzw-vqaaa-aaaaa-c".to_string());
+        static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
     }
 
     pub fn timestamp() -> u64 {
@@ -45,6 +235,14 @@ pub mod mock_ic0 {
         CALLER.with_borrow_mut(|s| *s = caller);
     }
 
+    pub fn is_controller(principal: &Principal) -> bool {
+        CONTROLLERS.with_borrow(|c| c.contains(principal))
+    }
+
+    pub fn add_controller(principal: Principal) {
+        CONTROLLERS.with_borrow_mut(|c| c.push(principal));
+    }
+
     pub fn reset_caller() {
         CALLER.with_borrow_mut(|s| *s = "2chl6-4hpzw-vqaaa-aaaaa-c".to_string());
     }
@@ -52,6 +250,25 @@ pub mod mock_ic0 {
     pub fn reset_timestamp_to(time: u64) {
         TIMESTAMP.with(|c| c.set(time));
     }
+
+    /// Drives a future to completion, mocked async calls are expected to be ready right away.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    pub fn log(message: &str) {
+        LOGS.with_borrow_mut(|l| l.push(message.to_string()));
+    }
+
+    pub fn logs() -> Vec<String> {
+        LOGS.with_borrow(|l| l.clone())
+    }
 }
 
 #[cfg(test)]
@@ -64,6 +281,30 @@ mod tests {
         assert_eq!(tokens, 7);
     }
 
+    #[test]
+    fn streaming_token_count_should_match_token_count() {
+        [
+            "This is a test      with spaces",
+            "",
+            "Résumé review: 3 bullet points, please!",
+            "fn main() {\n    println!(\"hi\");\n}",
+        ]
+        .iter()
+        .for_each(|text| {
+            assert_eq!(token_count(text).unwrap(), count_tokens_streaming(text));
+        });
+    }
+
+    #[test]
+    fn truncate_tokens_should_keep_whole_characters() {
+        assert_eq!(
+            Some("career career career"),
+            truncate_tokens("career career career career", 3)
+        );
+        assert_eq!(Some("🎉"), truncate_tokens("🎉🎉🎉", 4));
+        assert_eq!(None, truncate_tokens("career career", 2));
+    }
+
     #[test]
     fn tokenize_valid() {
         let tokens = bpe_tokenize("This is a test      with spaces").unwrap();
@@ -73,6 +314,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn terms_should_split_and_lowercase() {
+        let t: Vec<String> = terms("How to negotiate SALARY? (tips, e.g. Levels.fyi)").collect();
+        assert_eq!(
+            vec![
+                "how",
+                "to",
+                "negotiate",
+                "salary",
+                "tips",
+                "e",
+                "g",
+                "levels",
+                "fyi"
+            ],
+            t
+        );
+    }
+
+    #[test]
+    fn highlight_should_cut_on_char_boundaries() {
+        let text = "Réunion à Zürich: négociation du salaire café";
+        let (snippet, offset) = highlight(text, &["négociation".to_string()], 4).unwrap();
+        assert_eq!("…ch: <mark>négociation</mark> du …", snippet);
+        assert_eq!(18, offset);
+
+        let (snippet, offset) = highlight("日本語の面接 準備", &["準備".to_string()], 2).unwrap();
+        assert_eq!("…接 <mark>準備</mark>", snippet);
+        assert_eq!(7, offset);
+
+        let (snippet, _) = highlight("🎉 Offer accepted!", &["offer".to_string()], 10).unwrap();
+        assert_eq!("🎉 <mark>Offer</mark> accepted!", snippet);
+        assert_eq!(None, highlight("nothing here", &["offer".to_string()], 10));
+    }
+
+    #[test]
+    fn redact_resume_should_mask_emails() {
+        let redacted = redact_resume("Contact: fulan.dev@mail.co.id, or <hr@corp.io>.");
+        assert_eq!("Contact: [email], or <[email]>.", redacted);
+        assert_eq!("@handle and a@b", redact_resume("@handle and a@b"));
+    }
+
+    #[test]
+    fn redact_resume_should_mask_phone_numbers() {
+        let redacted = redact_resume("Phone +62 812-3456-7890 / (555) 123-4567 ext");
+        assert_eq!("Phone [phone] / [phone] ext", redacted);
+        assert_eq!(
+            "Worked 2019-2023 at v2.0",
+            redact_resume("Worked 2019-2023 at v2.0")
+        );
+    }
+
+    #[test]
+    fn redact_resume_should_keep_year_ranges_and_dates() {
+        let resume = "Acme 2019-2023 2020 - 2024, from 2023-01-15 2024-02-20 (1 2 3 4 5 6 7 8 9)";
+        assert_eq!(resume, redact_resume(resume));
+        assert_eq!("Call [phone]", redact_resume("Call 0812-3456-7890"));
+        assert_eq!("Call [phone]", redact_resume("Call 081234567890"));
+    }
+
     #[test]
     #[should_panic]
     fn timestamp_canister_only() {